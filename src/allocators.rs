@@ -41,72 +41,227 @@ impl<'data, const SIZE: usize> BoundArena<'data, SIZE> {
     }
 }
 
-const KERNEL_ARENA_SIZE: usize = 4 * 1024;
+/// An owning pointer to a value on the kernel heap, like `alloc::boxed::Box`. Dropping it drops the
+/// value and returns its memory to the global allocator. Zero sized values don't allocate.
+pub struct KBox<T: ?Sized> {
+    ptr: NonNull<T>,
+    _marker: PhantomData<T>,
+}
+
+// SAFETY: `KBox` owns its value, so it is as thread safe as the value itself.
+unsafe impl<T: ?Sized + Send> Send for KBox<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for KBox<T> {}
 
-pub static mut KERNEL_HEAP: [MaybeUninit<u8>; KERNEL_ARENA_SIZE] = MaybeUninit::uninit_array();
-pub static mut KERNEL_ARENA: BoundArena<'static, KERNEL_ARENA_SIZE> =
-    unsafe { BoundArena::new(&mut KERNEL_HEAP) };
+impl<T> KBox<T> {
+    /// Moves `val` to the heap.
+    ///
+    /// # Panics
+    ///
+    /// Calls the allocation error handler if the heap is exhausted.
+    pub fn new(val: T) -> Self {
+        let layout = Layout::new::<T>();
+        match Self::try_new(val) {
+            Ok(b) => b,
+            Err(_) => alloc::alloc::handle_alloc_error(layout),
+        }
+    }
 
-#[derive(Debug)]
-pub struct KBox<'a, T: ?Sized>(&'a mut T);
+    /// Moves `val` to the heap, or gives it back if the heap is exhausted.
+    pub fn try_new(val: T) -> Result<Self, T> {
+        let layout = Layout::new::<T>();
+        let ptr = if layout.size() == 0 {
+            NonNull::dangling()
+        } else {
+            // SAFETY: The layout is not zero sized.
+            match NonNull::new(unsafe { alloc::alloc::alloc(layout) }.cast::<T>()) {
+                Some(ptr) => ptr,
+                None => return Err(val),
+            }
+        };
+        // SAFETY: The pointer is valid for writes of a `T`, or `T` is zero sized.
+        unsafe { ptr.as_ptr().write(val) };
+        Ok(KBox {
+            ptr,
+            _marker: PhantomData,
+        })
+    }
 
-impl<'a, T> KBox<'a, T> {
+    /// Moves the value out of the box, freeing its memory.
+    pub fn into_inner(b: Self) -> T {
+        let ptr = KBox::into_raw(b);
+        // SAFETY: The pointer comes from `into_raw`, and the value is moved out before the memory
+        // is freed without dropping it again.
+        unsafe {
+            let val = ptr::read(ptr);
+            drop(KBox::from_raw(ptr.cast::<ManuallyDrop<T>>()));
+            val
+        }
+    }
+}
+
+impl<T: ?Sized> KBox<T> {
+    /// Consumes the box, returning the pointer to its value without freeing it.
+    pub fn into_raw(b: Self) -> *mut T {
+        ManuallyDrop::new(b).ptr.as_ptr()
+    }
+
+    /// Takes back ownership of a pointer returned by [`KBox::into_raw`].
+    ///
     /// # Safety
     ///
-    /// This operation is unsafe because it uses the global `KERNEL_ARENA` and
-    /// may cause a race condition if run in a multithreaded context.
-    pub unsafe fn new(val: T) -> Self {
-        KBox(
-            KERNEL_ARENA
-                .alloc(val)
-                .expect("failed to allocate `KBox` (out of memory)")
-        )
+    /// `ptr` must come from [`KBox::into_raw`] and must not be used afterwards.
+    pub unsafe fn from_raw(ptr: *mut T) -> Self {
+        KBox {
+            ptr: NonNull::new_unchecked(ptr),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Consumes the box, never freeing its value.
+    pub fn leak<'a>(b: Self) -> &'a mut T
+    where
+        T: 'a,
+    {
+        // SAFETY: The value is never freed.
+        unsafe { &mut *KBox::into_raw(b) }
+    }
+}
+
+impl<T: Clone> KBox<[T]> {
+    /// Copies `slice` to the heap.
+    pub fn from_slice(slice: &[T]) -> Self {
+        let layout = Layout::array::<T>(slice.len()).expect("slice too large");
+        let ptr = if layout.size() == 0 {
+            NonNull::<T>::dangling().as_ptr()
+        } else {
+            // SAFETY: The layout is not zero sized.
+            let ptr = unsafe { alloc::alloc::alloc(layout) }.cast::<T>();
+            if ptr.is_null() {
+                alloc::alloc::handle_alloc_error(layout);
+            }
+            ptr
+        };
+        for (i, val) in slice.iter().enumerate() {
+            // SAFETY: `i` is in bounds of the allocation. If `clone` panics the elements written
+            // so far are leaked, which is safe.
+            unsafe { ptr.add(i).write(val.clone()) };
+        }
+        // SAFETY: Every element was initialized above.
+        unsafe { KBox::from_raw(ptr::slice_from_raw_parts_mut(ptr, slice.len())) }
+    }
+}
+
+impl<T: Copy> KBox<[T]> {
+    /// Allocates a slice of `len` copies of `val`, or returns `None` if the heap is exhausted.
+    pub fn try_filled(len: usize, val: T) -> Option<Self> {
+        let layout = Layout::array::<T>(len).ok()?;
+        let ptr = if layout.size() == 0 {
+            NonNull::<T>::dangling().as_ptr()
+        } else {
+            // SAFETY: The layout is not zero sized.
+            let ptr = unsafe { alloc::alloc::alloc(layout) }.cast::<T>();
+            if ptr.is_null() {
+                return None;
+            }
+            ptr
+        };
+        for i in 0..len {
+            // SAFETY: `i` is in bounds of the allocation.
+            unsafe { ptr.add(i).write(val) };
+        }
+        // SAFETY: Every element was initialized above.
+        Some(unsafe { KBox::from_raw(ptr::slice_from_raw_parts_mut(ptr, len)) })
+    }
+}
+
+impl<T: Clone> From<&[T]> for KBox<[T]> {
+    fn from(slice: &[T]) -> Self {
+        KBox::from_slice(slice)
+    }
+}
+
+impl From<&str> for KBox<str> {
+    fn from(s: &str) -> Self {
+        let bytes = KBox::into_raw(KBox::from_slice(s.as_bytes()));
+        // SAFETY: The bytes are a copy of a `str`, so they are valid UTF-8, and `str` has the
+        // layout of `[u8]`.
+        unsafe { KBox::from_raw(bytes as *mut str) }
+    }
+}
+
+impl<T: ?Sized> Drop for KBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: The value is owned by the box and valid until here.
+        unsafe {
+            let layout = Layout::for_value(self.ptr.as_ref());
+            ptr::drop_in_place(self.ptr.as_ptr());
+            if layout.size() != 0 {
+                alloc::alloc::dealloc(self.ptr.as_ptr().cast::<u8>(), layout);
+            }
+        }
+    }
+}
+
+impl<T: Clone> Clone for KBox<T> {
+    fn clone(&self) -> Self {
+        KBox::new(self.as_ref().clone())
     }
 }
 
 use core::convert::{AsMut, AsRef};
-use core::fmt::{self, Display};
-use core::marker::Unsize;
+use core::fmt::{self, Debug, Display};
+use core::marker::{PhantomData, Unsize};
+use core::mem::ManuallyDrop;
 use core::ops::{CoerceUnsized, Deref, DerefMut, DispatchFromDyn};
+use core::ptr::{self, NonNull};
 
-impl<'a, T: ?Sized + Display> Display for KBox<'a, T> {
+impl<T: ?Sized + Display> Display for KBox<T> {
     #[inline(always)]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         Display::fmt(self.as_ref(), f)
     }
 }
 
-impl<'a, T: ?Sized> Deref for KBox<'a, T> {
+impl<T: ?Sized + Debug> Debug for KBox<T> {
+    #[inline(always)]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(self.as_ref(), f)
+    }
+}
+
+impl<T: ?Sized> Deref for KBox<T> {
     type Target = T;
 
     #[inline(always)]
     fn deref(&self) -> &T {
-        self.0
+        // SAFETY: The box owns a valid value.
+        unsafe { self.ptr.as_ref() }
     }
 }
 
-impl<'a, T: ?Sized> DerefMut for KBox<'a, T> {
+impl<T: ?Sized> DerefMut for KBox<T> {
     #[inline(always)]
     fn deref_mut(&mut self) -> &mut T {
-        self.0
+        // SAFETY: The box owns a valid value, and is borrowed mutably.
+        unsafe { self.ptr.as_mut() }
     }
 }
 
-impl<'a, T: ?Sized> AsRef<T> for KBox<'a, T> {
+impl<T: ?Sized> AsRef<T> for KBox<T> {
     #[inline(always)]
     fn as_ref(&self) -> &T {
-        self.0
+        self
     }
 }
 
-impl<'a, T: ?Sized> AsMut<T> for KBox<'a, T> {
+impl<T: ?Sized> AsMut<T> for KBox<T> {
     #[inline(always)]
     fn as_mut(&mut self) -> &mut T {
-        self.0
+        self
     }
 }
 
-impl<'a, T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<KBox<'a, U>> for KBox<'a, T> {}
+impl<T: ?Sized + Unsize<U>, U: ?Sized> CoerceUnsized<KBox<U>> for KBox<T> {}
 
-// FIXME: I don't really know what this is for. So there might be a bug here.
-impl<'a, T: ?Sized + Unsize<U>, U: ?Sized> DispatchFromDyn<KBox<'a, U>> for KBox<'a, T> {}
+// Allows methods taking `self: KBox<Self>` to be called on `KBox<dyn Trait>`.
+impl<T: ?Sized + Unsize<U>, U: ?Sized> DispatchFromDyn<KBox<U>> for KBox<T> {}
@@ -0,0 +1,162 @@
+//! Stack backtraces, walking the frame records of AArch64.
+//!
+//! The kernel is built with frame pointers, so `x29` points to a frame record holding the frame
+//! pointer and the return address of the caller. The boot code clears `x29` before entering Rust,
+//! which ends the chain.
+//!
+//! Addresses are symbolized with [`KERNEL_SYMBOLS`], a table reserved here and filled in by
+//! `cargo xtask build` from the symbol table of the kernel ELF, since the binary loaded by the
+//! firmware has no symbols. Its layout, after the magic, is:
+//!
+//! - `count: u32` and 4 bytes of padding.
+//! - `count` entries sorted by address, each `addr: u64`, `size: u32` and `name_off: u32`.
+//! - The demangled names, each prefixed by its length as a `u8`. `name_off` is relative to the
+//!   start of the names.
+
+use core::{fmt, mem::size_of};
+
+use crate::drivers::MMIO_BASE_ADDR;
+use crate::memory::mmu::{self, PAGE_SIZE};
+
+/// Maximum number of frames walked.
+pub const MAX_FRAMES: usize = 32;
+/// Size of the symbol table data.
+const SYMBOL_TABLE_SIZE: usize = 128 * 1024;
+const SYMBOL_ENTRY_SIZE: usize = 16;
+
+/// The symbol table, patched in the ELF by `xtask`. The magic makes sure it is not in `.bss`, and
+/// lets `xtask` check that it found the right place.
+#[repr(C)]
+pub struct SymbolTable {
+    magic: [u8; 8],
+    data: [u8; SYMBOL_TABLE_SIZE],
+}
+
+/// The kernel symbol table.
+#[no_mangle]
+#[used]
+#[link_section = ".rodata.symbols"]
+pub static KERNEL_SYMBOLS: SymbolTable = SymbolTable {
+    magic: *b"KSYMTAB\0",
+    data: [0; SYMBOL_TABLE_SIZE],
+};
+
+/// A function containing an address.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    /// Demangled name of the function.
+    pub name: &'static str,
+    /// Offset of the address from the start of the function.
+    pub offset: usize,
+}
+
+/// Gets the table data. The compiler must not assume that it is still zeroed, since it is patched
+/// after the build.
+fn symbol_data() -> &'static [u8; SYMBOL_TABLE_SIZE] {
+    let data = core::hint::black_box(&KERNEL_SYMBOLS.data as *const [u8; SYMBOL_TABLE_SIZE]);
+    // SAFETY: The pointer is to a static.
+    unsafe { &*data }
+}
+
+fn read_u32(data: &[u8], off: usize) -> Option<u32> {
+    let bytes = data.get(off..off + size_of::<u32>())?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], off: usize) -> Option<u64> {
+    Some(read_u32(data, off)? as u64 | (read_u32(data, off + 4)? as u64) << 32)
+}
+
+/// Finds the function containing `addr`.
+pub fn symbolize(addr: usize) -> Option<Symbol> {
+    let data = symbol_data();
+    let count = read_u32(data, 0)? as usize;
+    let entries = 8;
+    let names = entries + count * SYMBOL_ENTRY_SIZE;
+    let entry = |idx: usize| -> Option<(u64, u32, u32)> {
+        let off = entries + idx * SYMBOL_ENTRY_SIZE;
+        Some((
+            read_u64(data, off)?,
+            read_u32(data, off + 8)?,
+            read_u32(data, off + 12)?,
+        ))
+    };
+
+    // The last entry starting at or before `addr`.
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if entry(mid)?.0 <= addr as u64 {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    let (start, size, name_off) = entry(lo.checked_sub(1)?)?;
+    let offset = (addr as u64 - start) as usize;
+    if offset >= size as usize {
+        return None;
+    }
+    let name_off = names + name_off as usize;
+    let len = *data.get(name_off)? as usize;
+    let name = core::str::from_utf8(data.get(name_off + 1..name_off + 1 + len)?).ok()?;
+    Some(Symbol { name, offset })
+}
+
+/// Calls `f` with the return address of every frame, starting with the frame record at `fp`.
+pub fn walk(mut fp: usize, mut f: impl FnMut(usize)) {
+    for _ in 0..MAX_FRAMES {
+        // Frame records are on the stacks, which are in normal memory above the null page. The
+        // stack grows down, so each caller's record is above the previous one. A corrupt `fp` may
+        // still point to an unmapped page, like the guard page of a stack that overflowed.
+        if fp % 8 != 0
+            || !(PAGE_SIZE..MMIO_BASE_ADDR - 16).contains(&fp)
+            || !mmu::is_readable(fp)
+            || !mmu::is_readable(fp + 8)
+        {
+            return;
+        }
+        // SAFETY: Checked above that both words are in mapped normal memory.
+        let (next, lr) = unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+        if lr == 0 {
+            return;
+        }
+        f(lr);
+        if next <= fp {
+            return;
+        }
+        fp = next;
+    }
+}
+
+/// Formats a code address with its symbol.
+pub struct Location(pub usize);
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)?;
+        match symbolize(self.0) {
+            Some(sym) => write!(f, " {}+{:#x}", sym.name, sym.offset),
+            None => f.write_str(" <unknown>"),
+        }
+    }
+}
+
+/// Prints the backtrace starting with the frame record at `fp`. Return addresses point after the
+/// call, so they are symbolized one instruction back, at the call itself.
+pub fn print_from(fp: usize) {
+    let mut idx = 0;
+    walk(fp, |lr| {
+        crate::println!("  #{:<2} {}", idx, Location(lr.wrapping_sub(4)));
+        idx += 1;
+    });
+}
+
+/// Prints the backtrace of the caller.
+#[inline(always)]
+pub fn print() {
+    let fp: usize;
+    // SAFETY: Only reads the frame pointer.
+    unsafe { core::arch::asm!("mov {}, x29", out(reg) fp) };
+    print_from(fp);
+}
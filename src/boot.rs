@@ -1,9 +1,25 @@
-use core::{
-    ptr,
-    sync::atomic::{AtomicPtr, Ordering},
-};
-
-use crate::utils::get_cpu;
+//! Boot code and exception level configuration.
+//!
+//! Execution starts at `_start` in `boot/boot.S` on every core. Both the Raspberry Pi firmware and
+//! `qemu -M raspi3b` enter the kernel at EL2, but the kernel is meant to run at EL1, so before
+//! anything else each core drops to EL1:
+//!
+//! - Entered at EL2: `HCR_EL2.RW` is set so EL1 runs AArch64, `CNTHCTL_EL2.EL1PCEN` and
+//!   `CNTHCTL_EL2.EL1PCTEN` give EL1 access to the physical timer and counter, `CNTVOFF_EL2` is
+//!   cleared so the virtual and physical counters agree, `SCTLR_EL1` is reset to a known value with
+//!   the MMU and caches off and `VBAR_EL2` points to the EL2 vector table so traps to EL2 are still
+//!   reported, with `SP_EL2` on a stack reserved for each core by the linker script. `SPSR_EL2`
+//!   masks all interrupts (`DAIF`) and selects EL1h, so `SP_EL1` is the stack pointer after the
+//!   `eret`, and `ELR_EL2` points back into `_start`.
+//! - Entered at EL3: the same EL2 state is configured, `SCR_EL3` makes the lower levels non-secure
+//!   and AArch64, and the core returns directly to EL1 through `SPSR_EL3`/`ELR_EL3`.
+//! - Entered at EL1: nothing is changed.
+//!
+//! After that the boot core zeroes `.bss`, sets up its stack and jumps to [`_start_rust`] with the
+//! address of the device tree passed by the firmware in `x0`, or zero if there is none, while the
+//! other cores wait in `_child_spin`, already at EL1, until they are started through [`crate::smp`].
+
+use crate::smp;
 
 #[no_mangle]
 #[link_section = ".text._start_arguments"]
@@ -11,41 +27,28 @@ pub static BOOT_CORE_ID: u64 = 0;
 
 core::arch::global_asm!(include_str!("boot/boot.S"));
 
-extern "C" {
-    /// Some docs
-    #[link_name = "_child_target"]
-    #[allow(improper_ctypes)]
-    static mut CHILD_TARGET: unsafe fn();
-}
-
-pub const CHILD_STACK_SIZE: usize = 64 * 1024;
-#[no_mangle]
-pub static CHILD_STACK_SIZE_E: usize = CHILD_STACK_SIZE;
-
-pub static CHILD_TASKS: [AtomicPtr<()>; 4] = [const { AtomicPtr::new(ptr::null_mut()) }; 4];
+/// Entry point of the Rust language in the kernel. This function is called from assembly, with the
+/// address of the device tree.
 #[no_mangle]
-pub static CHILD_STACKS: [[u8; CHILD_STACK_SIZE]; 4] = [[0; CHILD_STACK_SIZE]; 4];
-
-/// Entry point of the Rust language in the kernel. This function is called from assembly.
-#[no_mangle]
-pub unsafe fn _start_rust() -> ! {
-    CHILD_TARGET = child_loop;
-    crate::kernel_init();
+pub unsafe extern "C" fn _start_rust(dtb: usize) -> ! {
+    crate::exception::init();
+    crate::memory::mmu::init().expect("failed to build the kernel translation tables");
+    crate::memory::mmu::enable();
+    crate::memory::heap::init();
+    crate::kernel_init(dtb);
 }
 
-#[no_mangle]
-pub unsafe fn child_loop() {
-    let cpu = get_cpu();
+/// Entry point of the secondary cores, started by `kernel_main` through [`crate::smp`]. Runs the
+/// jobs sent to this core with [`crate::smp::spawn_on`], sleeping while there are none.
+pub fn child_loop(cpu: usize) -> ! {
+    crate::irq::local_enable();
     loop {
-        // NOTE: If I don't use read_volatile here, for some reason, rust assumes that no other
-        // thread can write and change `CHILD_TASKS` so it optimizes it away. This is very weird
-        // and unexpected behaviour!
-        // FIXME: There may be a better memory ordering for this.
-        let ptr = CHILD_TASKS[cpu as usize].load(Ordering::SeqCst);
-        if !ptr.is_null() {
-            CHILD_TASKS[cpu as usize].store(ptr::null_mut(), Ordering::SeqCst);
-            (core::mem::transmute::<*mut (), fn()>(ptr))();
+        if crate::panic::is_panicking() {
+            crate::panic::halt_core();
+        }
+        // SAFETY: This is the only place that consumes the jobs of `cpu`, and it runs on `cpu`.
+        if !unsafe { smp::work::run_next_job(cpu) } {
+            cortex_a::asm::wfe();
         }
-        cortex_a::asm::wfe();
     }
 }
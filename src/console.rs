@@ -0,0 +1,260 @@
+//! Consoles, the sinks behind [`print!`](crate::print) and [`println!`](crate::println).
+//!
+//! Output is written to every registered [`Console`], and input is read from whichever of them has
+//! something available. Until the first console is registered, output is kept in an early buffer,
+//! which is replayed to it, so printing never fails, not even before the UART is initialized.
+//!
+//! The registry lock is held while formatting, so that the lines of different cores don't
+//! interleave, and IRQs are masked meanwhile, so that interrupt handlers on the same core can
+//! print without deadlocking.
+
+use core::fmt::{self, Write};
+
+use crate::drivers::Pins;
+use crate::error::{Error, ErrorKind, KError};
+use crate::irq::{self, IrqError};
+
+/// Maximum number of registered consoles.
+const MAX_CONSOLES: usize = 4;
+/// Size of the buffer that keeps the output printed before any console is registered.
+const EARLY_BUFFER_SIZE: usize = 4096;
+
+/// A sink for the kernel output, and possibly a source of input.
+pub trait Console: Sync {
+    /// Name of the console, which identifies it in the registry.
+    fn name(&self) -> &'static str;
+
+    /// Writes all of `buf`, blocking if needed.
+    fn write_bytes(&self, buf: &[u8]);
+
+    /// Reads a byte if one is available, without blocking.
+    fn read_byte(&self) -> Option<u8> {
+        None
+    }
+
+    /// Blocks until everything written was sent.
+    fn flush(&self) {}
+
+    /// Releases the locks of the console, so that it can be written to even if they were held
+    /// when the kernel panicked.
+    ///
+    /// # Safety
+    ///
+    /// No other core may be using the console. The state protected by the locks may be
+    /// inconsistent, so only use it to report a panic.
+    unsafe fn force_unlock(&self) {}
+}
+
+/// Errors that can happen when registering a console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    /// There are already [`MAX_CONSOLES`] consoles registered.
+    TooManyConsoles,
+    /// A console with the same name is already registered.
+    AlreadyRegistered,
+}
+
+impl fmt::Display for ConsoleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ConsoleError::TooManyConsoles => "too many consoles",
+            ConsoleError::AlreadyRegistered => "console is already registered",
+        })
+    }
+}
+
+impl Error for ConsoleError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ConsoleError::TooManyConsoles => ErrorKind::OutOfMemory,
+            ConsoleError::AlreadyRegistered => ErrorKind::AlreadyExists,
+        }
+    }
+}
+
+struct Registry {
+    consoles: [Option<&'static dyn Console>; MAX_CONSOLES],
+    early: [u8; EARLY_BUFFER_SIZE],
+    early_len: usize,
+    /// Bytes that didn't fit in the early buffer.
+    early_dropped: usize,
+}
+
+impl Registry {
+    fn is_empty(&self) -> bool {
+        self.consoles.iter().all(Option::is_none)
+    }
+
+    fn active(&self) -> impl Iterator<Item = &'static dyn Console> + '_ {
+        self.consoles.iter().flatten().copied()
+    }
+}
+
+impl fmt::Write for Registry {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.is_empty() {
+            let len = s.len().min(EARLY_BUFFER_SIZE - self.early_len);
+            self.early[self.early_len..self.early_len + len].copy_from_slice(&s.as_bytes()[..len]);
+            self.early_len += len;
+            self.early_dropped += s.len() - len;
+        } else {
+            for console in self.active() {
+                console.write_bytes(s.as_bytes());
+            }
+        }
+        Ok(())
+    }
+}
+
+static REGISTRY: spin::Mutex<Registry> = spin::Mutex::new(Registry {
+    consoles: [None; MAX_CONSOLES],
+    early: [0; EARLY_BUFFER_SIZE],
+    early_len: 0,
+    early_dropped: 0,
+});
+
+/// Registers `console`. The first console registered also gets everything printed before it.
+pub fn register(console: &'static dyn Console) -> Result<(), ConsoleError> {
+    irq::without_interrupts(|| {
+        let mut registry = REGISTRY.lock();
+        if registry.active().any(|c| c.name() == console.name()) {
+            return Err(ConsoleError::AlreadyRegistered);
+        }
+        let was_empty = registry.is_empty();
+        let slot = registry
+            .consoles
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ConsoleError::TooManyConsoles)?;
+        *slot = Some(console);
+
+        if was_empty {
+            console.write_bytes(&registry.early[..registry.early_len]);
+            if registry.early_dropped > 0 {
+                let dropped = registry.early_dropped;
+                let _ = writeln!(registry, "[WARN] {} bytes of early output lost", dropped);
+            }
+            registry.early_len = 0;
+            registry.early_dropped = 0;
+        }
+        Ok(())
+    })
+}
+
+/// Unregisters the console with the same name as `console`, if any.
+pub fn unregister(console: &'static dyn Console) {
+    irq::without_interrupts(|| {
+        for slot in REGISTRY.lock().consoles.iter_mut() {
+            if matches!(slot, Some(c) if c.name() == console.name()) {
+                *slot = None;
+            }
+        }
+    })
+}
+
+/// Releases the lock of the registry and the locks of every registered console, see
+/// [`Console::force_unlock`].
+///
+/// # Safety
+///
+/// Must only be called to report a panic, once the other cores are stopped.
+pub unsafe fn force_unlock() {
+    if REGISTRY.is_locked() {
+        REGISTRY.force_unlock();
+    }
+    for console in REGISTRY.lock().active() {
+        console.force_unlock();
+    }
+}
+
+/// Checks whether any console is registered.
+pub fn is_active() -> bool {
+    irq::without_interrupts(|| !REGISTRY.lock().is_empty())
+}
+
+/// Blocks until every console sent what was written to it.
+pub fn flush() {
+    irq::without_interrupts(|| {
+        for console in REGISTRY.lock().active() {
+            console.flush();
+        }
+    })
+}
+
+/// Reads a byte from the first console that has one, without blocking.
+pub fn try_read_byte() -> Option<u8> {
+    irq::without_interrupts(|| REGISTRY.lock().active().find_map(|c| c.read_byte()))
+}
+
+/// Reads into `buf` the bytes that are available, sleeping until there is at least one. Returns
+/// the number of bytes read, which is only zero if `buf` is empty.
+pub fn read(buf: &mut [u8]) -> usize {
+    let mut read = 0;
+    while read < buf.len() {
+        match try_read_byte() {
+            Some(byte) => {
+                buf[read] = byte;
+                read += 1;
+            }
+            // Woken up by the UART interrupts, or by the event stream if they are not enabled.
+            None if read == 0 => cortex_a::asm::wfe(),
+            None => break,
+        }
+    }
+    read
+}
+
+/// Initializes the UART backing the console, the Mini UART, or the PL011 when the `console-pl011`
+/// feature is enabled, and registers it. Both use GPIO pins 14 and 15, which are claimed from
+/// `pins`, so only one of them can be initialized.
+pub fn init_uart(pins: &mut Pins) -> Result<(), KError> {
+    #[cfg(not(feature = "console-pl011"))]
+    {
+        use crate::drivers::mini_uart::{MiniUART, MINI_UART_CONSOLE};
+        const BAUD_RATE: u32 = 115_200;
+
+        let tx = pins.pin::<14, _>()?;
+        let rx = pins.pin::<15, _>()?;
+        let (divisor, res) = match MiniUART::baud_divisor(BAUD_RATE) {
+            Ok((divisor, baud)) => (divisor, Ok(baud)),
+            Err(e) => (MiniUART::DEFAULT_BAUD_DIVISOR, Err(e)),
+        };
+        MiniUART::acquire().init(tx, rx, divisor);
+        register(&MINI_UART_CONSOLE)?;
+        match res {
+            Ok(baud) => log::debug!(
+                "console at {} baud, {} ppm off",
+                baud.actual,
+                baud.error_ppm()
+            ),
+            Err(e) => log::warn!("{:#}, assuming the default core clock", KError::from(e)),
+        }
+        Ok(())
+    }
+    #[cfg(feature = "console-pl011")]
+    {
+        use crate::drivers::pl011::{Pl011, PL011_CONSOLE};
+
+        let tx = pins.pin::<14, _>()?;
+        let rx = pins.pin::<15, _>()?;
+        Pl011::acquire().init_default(tx, rx);
+        register(&PL011_CONSOLE)?;
+        Ok(())
+    }
+}
+
+/// Switches the UART initialized by [`init_uart`] to interrupt driven input. Must be called once,
+/// after [`irq::init`].
+pub fn init_uart_irq() -> Result<(), IrqError> {
+    #[cfg(not(feature = "console-pl011"))]
+    return crate::drivers::mini_uart::init_irq();
+    #[cfg(feature = "console-pl011")]
+    return crate::drivers::pl011::init_irq();
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    irq::without_interrupts(|| {
+        let _ = REGISTRY.lock().write_fmt(args);
+    })
+}
@@ -0,0 +1,273 @@
+//! In-memory kernel log, which keeps the latest log records even when no console is connected.
+//!
+//! Every record gets a sequence number and is written to the slot `seq % SLOT_COUNT`, overwriting
+//! the record `SLOT_COUNT` places older. Each slot is a sequence lock: its `state` is
+//! `(seq + 1) * 2 + 1` while the record is being written and `(seq + 1) * 2` once it is complete,
+//! with zero meaning that the slot was never written, so writers on any core,
+//! including interrupt handlers, never wait for a lock, and readers detect records that were
+//! overwritten while reading them. Readers that see a gap in the sequence numbers know that the
+//! records in between were dropped.
+//!
+//! The buffer is the `#[repr(C)]` static [`KERNEL_DMESG`], zero initialized so it lives in `.bss`,
+//! which a debugger can find by its symbol and decode with the layout of [`Slot`].
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    sync::atomic::{self, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use log::Level;
+
+/// Number of records kept.
+pub const SLOT_COUNT: usize = 256;
+/// Maximum length of the text of a record, longer records are truncated.
+pub const TEXT_SIZE: usize = 104;
+
+/// Times a writer retries to claim a slot that another writer is still filling before dropping the
+/// record. That only happens when the buffer wrapped around while the other writer was interrupted.
+const CLAIM_ATTEMPTS: usize = 1000;
+
+/// A slot of the buffer, 128 bytes long.
+#[repr(C)]
+struct Slot {
+    state: AtomicU64,
+    /// Time since boot, in microseconds.
+    timestamp: UnsafeCell<u64>,
+    level: UnsafeCell<u8>,
+    cpu: UnsafeCell<u8>,
+    len: UnsafeCell<u16>,
+    _padding: u32,
+    text: UnsafeCell<[u8; TEXT_SIZE]>,
+}
+
+/// The kernel log buffer.
+#[repr(C)]
+pub struct Dmesg {
+    /// Sequence number of the next record.
+    next: AtomicU64,
+    /// Records dropped because their slot couldn't be claimed.
+    dropped: AtomicU64,
+    slots: [Slot; SLOT_COUNT],
+}
+
+// SAFETY: The contents of a slot are only written by the writer that claimed it through `state`,
+// and readers discard what they read if `state` changed meanwhile.
+unsafe impl Sync for Dmesg {}
+
+/// The kernel log buffer.
+#[no_mangle]
+pub static KERNEL_DMESG: Dmesg = Dmesg {
+    next: AtomicU64::new(0),
+    dropped: AtomicU64::new(0),
+    slots: [const {
+        Slot {
+            state: AtomicU64::new(0),
+            timestamp: UnsafeCell::new(0),
+            level: UnsafeCell::new(0),
+            cpu: UnsafeCell::new(0),
+            len: UnsafeCell::new(0),
+            _padding: 0,
+            text: UnsafeCell::new([0; TEXT_SIZE]),
+        }
+    }; SLOT_COUNT],
+};
+
+/// A copy of a record of the kernel log.
+#[derive(Clone)]
+pub struct Record {
+    /// Sequence number of the record.
+    pub seq: u64,
+    /// Level of the record.
+    pub level: Level,
+    /// Core that logged the record.
+    pub cpu: u8,
+    /// Time since boot when the record was logged.
+    pub timestamp: Duration,
+    len: usize,
+    text: [u8; TEXT_SIZE],
+}
+
+impl Record {
+    /// Text of the record. A character split by the truncation is left out.
+    pub fn text(&self) -> &str {
+        let text = &self.text[..self.len];
+        match core::str::from_utf8(text) {
+            Ok(text) => text,
+            // SAFETY: Everything up to `valid_up_to` is valid UTF-8.
+            Err(e) => unsafe { core::str::from_utf8_unchecked(&text[..e.valid_up_to()]) },
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:>7}.{:06} {:<5} core {}] {}",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_micros(),
+            self.level,
+            self.cpu,
+            self.text()
+        )
+    }
+}
+
+/// Errors when reading a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadError {
+    /// The record was not written yet.
+    NotYetWritten,
+    /// The record was overwritten by a newer one.
+    Overwritten,
+}
+
+/// Writes formatted text into a slot, truncating what doesn't fit.
+struct SlotWriter<'a> {
+    buf: &'a mut [u8; TEXT_SIZE],
+    len: usize,
+}
+
+impl fmt::Write for SlotWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(TEXT_SIZE - self.len);
+        self.buf[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+impl Dmesg {
+    /// Appends a record.
+    pub fn write(&self, level: Level, cpu: u8, timestamp: Duration, args: fmt::Arguments) {
+        let seq = self.next.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[(seq % SLOT_COUNT as u64) as usize];
+        let tag = (seq + 1) << 1;
+
+        let mut claimed = false;
+        for _ in 0..CLAIM_ATTEMPTS {
+            let state = slot.state.load(Ordering::Relaxed);
+            // A newer record already claimed the slot, this one is as good as overwritten.
+            if state >> 1 > seq + 1 {
+                return;
+            }
+            if state & 1 == 0
+                && slot
+                    .state
+                    .compare_exchange(state, tag | 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                claimed = true;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        if !claimed {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // Orders the writes of the contents after claiming the slot.
+        atomic::fence(Ordering::Release);
+
+        // SAFETY: The slot is claimed, so no other writer touches it.
+        unsafe {
+            *slot.timestamp.get() = timestamp.as_micros() as u64;
+            *slot.level.get() = level as u8;
+            *slot.cpu.get() = cpu;
+            let mut writer = SlotWriter {
+                buf: &mut *slot.text.get(),
+                len: 0,
+            };
+            let _ = fmt::write(&mut writer, args);
+            *slot.len.get() = writer.len as u16;
+        }
+        slot.state.store(tag, Ordering::Release);
+    }
+
+    /// Reads the record `seq`.
+    pub fn read(&self, seq: u64) -> Result<Record, ReadError> {
+        let slot = &self.slots[(seq % SLOT_COUNT as u64) as usize];
+        let state = slot.state.load(Ordering::Acquire);
+        if state >> 1 > seq + 1 {
+            return Err(ReadError::Overwritten);
+        }
+        if state != (seq + 1) << 1 {
+            return Err(ReadError::NotYetWritten);
+        }
+
+        // SAFETY: The contents are plain bytes, and are discarded below if a writer changed them
+        // meanwhile.
+        let record = unsafe {
+            let level = match *slot.level.get() {
+                1 => Level::Error,
+                2 => Level::Warn,
+                3 => Level::Info,
+                4 => Level::Debug,
+                _ => Level::Trace,
+            };
+            Record {
+                seq,
+                level,
+                cpu: *slot.cpu.get(),
+                timestamp: Duration::from_micros(*slot.timestamp.get()),
+                len: (*slot.len.get() as usize).min(TEXT_SIZE),
+                text: *slot.text.get(),
+            }
+        };
+
+        atomic::fence(Ordering::Acquire);
+        if slot.state.load(Ordering::Relaxed) != state {
+            return Err(ReadError::Overwritten);
+        }
+        Ok(record)
+    }
+
+    /// Sequence number of the next record.
+    pub fn next_seq(&self) -> u64 {
+        self.next.load(Ordering::Acquire)
+    }
+
+    /// Sequence number of the oldest record that may still be in the buffer.
+    pub fn oldest_seq(&self) -> u64 {
+        self.next_seq().saturating_sub(SLOT_COUNT as u64)
+    }
+
+    /// Number of records dropped because their slot was busy.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Iterates over the records from `seq` on, skipping those that are no longer available. Gaps
+    /// in the sequence numbers of the records returned are records that were lost.
+    pub fn iter_from(&self, seq: u64) -> impl Iterator<Item = Record> + '_ {
+        let end = self.next_seq();
+        (seq.max(self.oldest_seq())..end).filter_map(move |seq| self.read(seq).ok())
+    }
+}
+
+/// Appends a record to [`KERNEL_DMESG`].
+pub fn write(level: Level, cpu: u8, timestamp: Duration, args: fmt::Arguments) {
+    KERNEL_DMESG.write(level, cpu, timestamp, args)
+}
+
+/// Prints the last `count` records to the console.
+pub fn dump_last(count: usize) {
+    let next = KERNEL_DMESG.next_seq();
+    let mut expected = next
+        .saturating_sub(count as u64)
+        .max(KERNEL_DMESG.oldest_seq());
+    for record in KERNEL_DMESG.iter_from(expected) {
+        if record.seq != expected {
+            crate::println!("... {} records lost ...", record.seq - expected);
+        }
+        crate::println!("{}", record);
+        expected = record.seq + 1;
+    }
+}
+
+/// Prints every record still in the buffer to the console.
+pub fn dump() {
+    dump_last(SLOT_COUNT)
+}
@@ -1,10 +1,21 @@
 #![allow(dead_code)]
 
+pub mod clocks;
+pub mod framebuffer;
 pub mod gpio;
+pub mod interrupt_controller;
+pub mod local_intc;
+pub mod mailbox;
 pub mod mini_uart;
+pub mod pl011;
+pub mod power;
+pub mod system_timer;
 
-pub use gpio::GPIO;
-pub use mini_uart::{mu_is_setup, mu_print, mu_println, mu_recv, mu_send, MiniUART};
+pub use gpio::{Pins, GPIO};
+pub use mini_uart::{
+    mu_flush, mu_is_setup, mu_print, mu_println, mu_read, mu_recv, mu_rx_overflows, mu_send,
+    mu_try_recv, MiniUART,
+};
 
 pub const MMIO_BASE_ADDR: usize = 0x3F000000;
 
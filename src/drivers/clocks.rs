@@ -0,0 +1,71 @@
+//! Clocks of the SoC, managed by the firmware through the mailbox.
+//!
+//! The firmware may scale some clocks on its own, like the core clock when the ARM is throttled, so
+//! anything derived from a rate, like the baud rate of the Mini UART, should query it right before
+//! using it. Rates are in Hz.
+
+use super::mailbox::{
+    self, ClockRateRequest, GetClockRate, GetClockRateMeasured, GetMaxClockRate, GetMinClockRate,
+    MailboxError, SetClockRate,
+};
+
+/// The clocks known by the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Clock {
+    /// Clock of the SD card controller.
+    Emmc = 1,
+    /// Reference clock of the PL011.
+    Uart = 2,
+    /// Clock of the ARM cores.
+    Arm = 3,
+    /// Clock of the VideoCore, which also drives the Mini UART and the other peripherals of the
+    /// VPU bus.
+    Core = 4,
+    /// Clock of the 3D engine.
+    V3d = 5,
+    /// Clock of the H.264 codec.
+    H264 = 6,
+    /// Clock of the image sensor pipeline.
+    Isp = 7,
+    /// Clock of the SDRAM.
+    Sdram = 8,
+    /// Pixel clock of the display.
+    Pixel = 9,
+    /// Clock of the PWM.
+    Pwm = 10,
+}
+
+/// Gets the rate `clock` is set to.
+pub fn rate(clock: Clock) -> Result<u32, MailboxError> {
+    Ok(mailbox::property::<GetClockRate>(clock as u32)?.value)
+}
+
+/// Gets the rate `clock` actually runs at, measured by the firmware.
+pub fn measured_rate(clock: Clock) -> Result<u32, MailboxError> {
+    Ok(mailbox::property::<GetClockRateMeasured>(clock as u32)?.value)
+}
+
+/// Gets the highest rate `clock` can be set to.
+pub fn max_rate(clock: Clock) -> Result<u32, MailboxError> {
+    Ok(mailbox::property::<GetMaxClockRate>(clock as u32)?.value)
+}
+
+/// Gets the lowest rate `clock` can be set to.
+pub fn min_rate(clock: Clock) -> Result<u32, MailboxError> {
+    Ok(mailbox::property::<GetMinClockRate>(clock as u32)?.value)
+}
+
+/// Sets the rate of `clock`, leaving the turbo settings alone. Returns the rate it got, which the
+/// firmware clamps between [`min_rate`] and [`max_rate`].
+///
+/// Changing the core clock changes the baud rate of the Mini UART, which must be initialized again
+/// afterwards.
+pub fn set_rate(clock: Clock, rate: u32) -> Result<u32, MailboxError> {
+    let request = ClockRateRequest {
+        id: clock as u32,
+        rate,
+        skip_setting_turbo: 1,
+    };
+    Ok(mailbox::property::<SetClockRate>(request)?.value)
+}
@@ -0,0 +1,548 @@
+//! Framebuffer allocated by the firmware through the mailbox, with drawing primitives and a text
+//! console.
+//!
+//! The firmware allocates the framebuffer in the memory of the VideoCore and scans it out to the
+//! display. It is mapped uncached, since the VideoCore doesn't see the caches of the ARM. Only one
+//! [`Framebuffer`] can exist, since it owns the memory, and it is never released once mapped.
+//!
+//! Reading uncached memory is slow, so drawing goes to a back buffer on the heap, and
+//! [`Framebuffer::present`] copies the rows that changed since the last call to the framebuffer.
+//!
+//! [`init_console`] allocates a framebuffer and registers a [`TextConsole`] drawing on it as a
+//! [`Console`], so that everything printed also shows up on the display. Each write to the console
+//! is presented right away, with the console registry locked, so a scroll copies the whole screen.
+//! The kernel only calls it with the `framebuffer-console` feature.
+
+pub mod font;
+
+use core::{
+    fmt,
+    ops::Range,
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::mailbox::{
+    self, AllocateBuffer, GetPitch, MailboxError, Message, Offset, ReleaseBuffer, SetDepth,
+    SetPhysicalSize, SetPixelOrder, SetVirtualOffset, SetVirtualSize, Size,
+};
+use crate::allocators::KBox;
+use crate::console::{self, Console};
+use crate::error::{Error, ErrorKind, KError};
+use crate::memory::{
+    align_down, align_up,
+    mmu::{self, Access, Attributes, MapError, MemoryType, PAGE_SIZE},
+};
+
+/// Bits per pixel asked to the firmware. Only 32 is supported.
+const DEPTH: u32 = 32;
+const PIXEL_ORDER_BGR: u32 = 0;
+const PIXEL_ORDER_RGB: u32 = 1;
+const BUFFER_ALIGN: u32 = PAGE_SIZE as u32;
+/// Clears the alias bits of the bus address of the framebuffer, giving the ARM physical address.
+const BUS_ADDRESS_MASK: u32 = 0x3fff_ffff;
+/// Columns between tab stops of the text console.
+const TAB_WIDTH: usize = 8;
+
+const FRAMEBUFFER_ATTRIBUTES: Attributes = Attributes {
+    memory: MemoryType::NormalUncached,
+    access: Access::ReadWrite,
+    executable: false,
+};
+
+/// Whether the framebuffer was allocated.
+static ALLOCATED: AtomicBool = AtomicBool::new(false);
+
+/// Errors of the framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramebufferError {
+    /// The framebuffer was already allocated.
+    InUse,
+    /// The firmware couldn't be asked for a framebuffer.
+    Mailbox(MailboxError),
+    /// The firmware didn't give a framebuffer with the requested configuration.
+    Unsupported,
+    /// The framebuffer couldn't be mapped.
+    Map(MapError),
+    /// There is not enough memory for the back buffer.
+    OutOfMemory,
+}
+
+impl fmt::Display for FramebufferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FramebufferError::InUse => "the framebuffer is already in use",
+            FramebufferError::Mailbox(_) => "failed to ask the firmware for a framebuffer",
+            FramebufferError::Unsupported => "the firmware refused the framebuffer configuration",
+            FramebufferError::Map(_) => "failed to map the framebuffer",
+            FramebufferError::OutOfMemory => "not enough memory for the back buffer",
+        })
+    }
+}
+
+impl Error for FramebufferError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            FramebufferError::InUse => ErrorKind::AlreadyExists,
+            FramebufferError::Mailbox(e) => e.kind(),
+            FramebufferError::Unsupported => ErrorKind::Unsupported,
+            FramebufferError::Map(e) => e.kind(),
+            FramebufferError::OutOfMemory => ErrorKind::OutOfMemory,
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FramebufferError::Mailbox(e) => Some(e),
+            FramebufferError::Map(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A color, with 8 bits per component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    /// Red component.
+    pub r: u8,
+    /// Green component.
+    pub g: u8,
+    /// Blue component.
+    pub b: u8,
+}
+
+impl Color {
+    /// Black.
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    /// White.
+    pub const WHITE: Color = Color::rgb(0xff, 0xff, 0xff);
+    /// Light gray.
+    pub const GRAY: Color = Color::rgb(0xaa, 0xaa, 0xaa);
+    /// Red.
+    pub const RED: Color = Color::rgb(0xff, 0, 0);
+    /// Green.
+    pub const GREEN: Color = Color::rgb(0, 0xff, 0);
+    /// Blue.
+    pub const BLUE: Color = Color::rgb(0, 0, 0xff);
+
+    /// Creates a color from its components.
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+}
+
+/// Order of the color components of a pixel in memory, from the lowest byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelOrder {
+    /// Blue, green, red.
+    Bgr,
+    /// Red, green, blue.
+    Rgb,
+}
+
+/// The framebuffer, with 32 bits per pixel, drawn through a back buffer.
+pub struct Framebuffer {
+    /// The pixels shown on the display, `stride` per row.
+    front: &'static mut [u32],
+    /// The pixels being drawn, `width` per row.
+    back: KBox<[u32]>,
+    /// Rows of `back` that changed since they were presented.
+    dirty: Range<usize>,
+    width: usize,
+    height: usize,
+    /// Pixels per row of `front`, at least `width`.
+    stride: usize,
+    order: PixelOrder,
+}
+
+/// The configuration answered by the firmware.
+struct Negotiated {
+    physical: Size,
+    depth: u32,
+    order: u32,
+    buffer: mailbox::MemoryRange,
+    pitch: u32,
+}
+
+/// Asks the firmware for a framebuffer of `width` by `height` pixels, all shown on the display.
+fn negotiate(width: u32, height: u32) -> Result<Negotiated, MailboxError> {
+    let size = Size { width, height };
+    let mut message = Message::new();
+    let physical = message.push::<SetPhysicalSize>(size)?;
+    message.push::<SetVirtualSize>(size)?;
+    message.push::<SetVirtualOffset>(Offset { x: 0, y: 0 })?;
+    let depth = message.push::<SetDepth>(DEPTH)?;
+    let order = message.push::<SetPixelOrder>(PIXEL_ORDER_RGB)?;
+    let buffer = message.push::<AllocateBuffer>(BUFFER_ALIGN)?;
+    let pitch = message.push::<GetPitch>(())?;
+    message.send()?;
+    Ok(Negotiated {
+        physical: message.response(&physical)?,
+        depth: message.response(&depth)?,
+        order: message.response(&order)?,
+        buffer: message.response(&buffer)?,
+        pitch: message.response(&pitch)?,
+    })
+}
+
+impl Framebuffer {
+    /// Allocates the framebuffer with a resolution of `width` by `height` pixels, and maps it.
+    pub fn new(width: u32, height: u32) -> Result<Self, FramebufferError> {
+        if ALLOCATED.swap(true, Ordering::AcqRel) {
+            return Err(FramebufferError::InUse);
+        }
+        let res = negotiate(width, height)
+            .map_err(FramebufferError::Mailbox)
+            .and_then(|config| {
+                let res = Framebuffer::from_config(&config);
+                if res.is_err() {
+                    // Nothing can be done if the firmware doesn't take it back.
+                    let _ = mailbox::property::<ReleaseBuffer>(());
+                }
+                res
+            });
+        if res.is_err() {
+            ALLOCATED.store(false, Ordering::Release);
+        }
+        res
+    }
+
+    /// Checks the configuration given by the firmware, and maps its buffer.
+    fn from_config(config: &Negotiated) -> Result<Self, FramebufferError> {
+        let order = match config.order {
+            PIXEL_ORDER_BGR => PixelOrder::Bgr,
+            PIXEL_ORDER_RGB => PixelOrder::Rgb,
+            _ => return Err(FramebufferError::Unsupported),
+        };
+        let (width, height) = (
+            config.physical.width as usize,
+            config.physical.height as usize,
+        );
+        let base = (config.buffer.base & BUS_ADDRESS_MASK) as usize;
+        let pitch = config.pitch as usize;
+        if config.depth != DEPTH
+            || base == 0
+            || width == 0
+            || height == 0
+            || pitch % 4 != 0
+            || pitch / 4 < width
+            || pitch * height > config.buffer.size as usize
+        {
+            return Err(FramebufferError::Unsupported);
+        }
+        let back = KBox::try_filled(width * height, 0).ok_or(FramebufferError::OutOfMemory)?;
+
+        let map_base = align_down(base, PAGE_SIZE);
+        let map_end = align_up(base + pitch * height, PAGE_SIZE);
+        mmu::map(
+            map_base,
+            map_base,
+            map_end - map_base,
+            FRAMEBUFFER_ATTRIBUTES,
+        )
+        .map_err(FramebufferError::Map)?;
+        let stride = pitch / 4;
+        // SAFETY: The memory was given to us by the firmware and is mapped, and `ALLOCATED` makes
+        // sure there is a single reference to it.
+        let front = unsafe { slice::from_raw_parts_mut(base as *mut u32, stride * height) };
+        Ok(Framebuffer {
+            front,
+            back,
+            dirty: 0..0,
+            width,
+            height,
+            stride,
+            order,
+        })
+    }
+
+    /// Width in pixels.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Height in pixels.
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Order of the color components in memory.
+    pub fn order(&self) -> PixelOrder {
+        self.order
+    }
+
+    /// Converts `color` to the value of a pixel.
+    pub fn encode(&self, color: Color) -> u32 {
+        let (low, high) = match self.order {
+            PixelOrder::Bgr => (color.b, color.r),
+            PixelOrder::Rgb => (color.r, color.b),
+        };
+        low as u32 | (color.g as u32) << 8 | (high as u32) << 16
+    }
+
+    /// Marks `rows` of the back buffer as changed, to be copied by [`Framebuffer::present`].
+    fn mark_dirty(&mut self, rows: Range<usize>) {
+        if rows.is_empty() {
+            return;
+        }
+        self.dirty = if self.dirty.is_empty() {
+            rows
+        } else {
+            self.dirty.start.min(rows.start)..self.dirty.end.max(rows.end)
+        };
+    }
+
+    /// Copies the rows drawn since the last call to the framebuffer, showing them on the display.
+    pub fn present(&mut self) {
+        for y in self.dirty.clone() {
+            self.front[y * self.stride..y * self.stride + self.width]
+                .copy_from_slice(&self.back[y * self.width..(y + 1) * self.width]);
+        }
+        self.dirty = 0..0;
+    }
+
+    /// Gets the pixels of row `y` in the back buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `y` is not less than the height.
+    pub fn row_mut(&mut self, y: usize) -> &mut [u32] {
+        assert!(y < self.height, "row {} is outside of the framebuffer", y);
+        self.mark_dirty(y..y + 1);
+        &mut self.back[y * self.width..(y + 1) * self.width]
+    }
+
+    /// Sets the pixel at `x`, `y`, doing nothing if it is outside of the framebuffer.
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color) {
+        if x < self.width && y < self.height {
+            self.back[y * self.width + x] = self.encode(color);
+            self.mark_dirty(y..y + 1);
+        }
+    }
+
+    /// Fills the rectangle of `width` by `height` pixels with its top left corner at `x`, `y`. The
+    /// parts outside of the framebuffer are ignored.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let pixel = self.encode(color);
+        let x_end = x.saturating_add(width).min(self.width);
+        let y_end = y.saturating_add(height).min(self.height);
+        for y in y.min(y_end)..y_end {
+            self.back[y * self.width + x.min(x_end)..y * self.width + x_end].fill(pixel);
+        }
+        self.mark_dirty(y.min(y_end)..y_end);
+    }
+
+    /// Fills the whole framebuffer.
+    pub fn clear(&mut self, color: Color) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Draws a line from `x0`, `y0` to `x1`, `y1`, both included, with Bresenham's algorithm.
+    pub fn line(&mut self, (x0, y0): (usize, usize), (x1, y1): (usize, usize), color: Color) {
+        let (mut x, mut y) = (x0 as isize, y0 as isize);
+        let (x1, y1) = (x1 as isize, y1 as isize);
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (step_x, step_y) = (if x < x1 { 1 } else { -1 }, if y < y1 { 1 } else { -1 });
+        let mut err = dx + dy;
+        loop {
+            self.set_pixel(x as usize, y as usize, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let err2 = 2 * err;
+            if err2 >= dy {
+                err += dy;
+                x += step_x;
+            }
+            if err2 <= dx {
+                err += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Copies an image of `width` pixels per row, with its top left corner at `x`, `y`. The parts
+    /// outside of the framebuffer are ignored.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, image: &[Color]) {
+        if width == 0 {
+            return;
+        }
+        for (row, src) in image.chunks(width).enumerate() {
+            let y = y + row;
+            if y >= self.height {
+                break;
+            }
+            for (col, &color) in src.iter().enumerate() {
+                self.set_pixel(x + col, y, color);
+            }
+        }
+    }
+
+    /// Draws the glyph of `c` from [`font`] with its top left corner at `x`, `y`. Characters
+    /// without a glyph are drawn as `?`.
+    pub fn draw_char(&mut self, x: usize, y: usize, c: u8, fg: Color, bg: Color) {
+        let c = if (font::FIRST..=font::LAST).contains(&c) {
+            c
+        } else {
+            b'?'
+        };
+        let glyph = &font::GLYPHS[(c - font::FIRST) as usize];
+        let (fg, bg) = (self.encode(fg), self.encode(bg));
+        for (row, bits) in glyph.iter().enumerate() {
+            let y = y + row;
+            if y >= self.height {
+                break;
+            }
+            for col in 0..font::WIDTH.min(self.width.saturating_sub(x)) {
+                let set = bits & (0x80 >> col) != 0;
+                self.back[y * self.width + x + col] = if set { fg } else { bg };
+            }
+        }
+        self.mark_dirty(y.min(self.height)..y.saturating_add(font::HEIGHT).min(self.height));
+    }
+
+    /// Moves the content up by `lines` pixels, filling the bottom with `fill`.
+    pub fn scroll_up(&mut self, lines: usize, fill: Color) {
+        let lines = lines.min(self.height);
+        self.back
+            .copy_within(lines * self.width..self.height * self.width, 0);
+        self.mark_dirty(0..self.height);
+        self.fill_rect(0, self.height - lines, self.width, lines, fill);
+    }
+}
+
+/// A text terminal drawn on a [`Framebuffer`] with [`font`], which scrolls when the cursor goes
+/// past the last line.
+///
+/// Handles `\n`, `\r`, `\t` and backspace, which only moves the cursor back. Bytes that are not
+/// printable ASCII are drawn as `?`, once per UTF-8 character.
+pub struct TextConsole {
+    fb: Framebuffer,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    fg: Color,
+    bg: Color,
+}
+
+impl TextConsole {
+    /// Creates a console covering the whole framebuffer, and clears it.
+    pub fn new(mut fb: Framebuffer, fg: Color, bg: Color) -> Self {
+        fb.clear(bg);
+        fb.present();
+        TextConsole {
+            cols: fb.width() / font::WIDTH,
+            rows: fb.height() / font::HEIGHT,
+            fb,
+            col: 0,
+            row: 0,
+            fg,
+            bg,
+        }
+    }
+
+    /// Number of columns and rows of characters.
+    pub fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    /// Sets the colors of the characters written from now on.
+    pub fn set_colors(&mut self, fg: Color, bg: Color) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    /// Shows what was written since the last call on the display, see [`Framebuffer::present`].
+    pub fn present(&mut self) {
+        self.fb.present();
+    }
+
+    /// Writes a single byte at the cursor. It only shows up on the display once presented.
+    pub fn write_byte(&mut self, byte: u8) {
+        if self.cols == 0 || self.rows == 0 {
+            return;
+        }
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.col = 0,
+            b'\t' => {
+                self.col = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                if self.col >= self.cols {
+                    self.new_line();
+                }
+            }
+            0x08 => self.col = self.col.saturating_sub(1),
+            // Continuation bytes of UTF-8 characters.
+            0x80..=0xbf => (),
+            byte => {
+                let (x, y) = (self.col * font::WIDTH, self.row * font::HEIGHT);
+                self.fb.draw_char(x, y, byte, self.fg, self.bg);
+                self.col += 1;
+                if self.col == self.cols {
+                    self.new_line();
+                }
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.fb.scroll_up(font::HEIGHT, self.bg);
+        }
+    }
+}
+
+impl fmt::Write for TextConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        self.present();
+        Ok(())
+    }
+}
+
+/// The [`TextConsole`] on the framebuffer as a [`Console`], once [`init_console`] is called.
+pub struct FramebufferConsole {
+    text: spin::Mutex<Option<TextConsole>>,
+}
+
+/// The framebuffer console.
+pub static FRAMEBUFFER_CONSOLE: FramebufferConsole = FramebufferConsole {
+    text: spin::Mutex::new(None),
+};
+
+impl Console for FramebufferConsole {
+    fn name(&self) -> &'static str {
+        "framebuffer"
+    }
+
+    fn write_bytes(&self, buf: &[u8]) {
+        if let Some(text) = self.text.lock().as_mut() {
+            for &byte in buf {
+                text.write_byte(byte);
+            }
+            text.present();
+        }
+    }
+
+    unsafe fn force_unlock(&self) {
+        if self.text.is_locked() {
+            self.text.force_unlock();
+        }
+    }
+}
+
+/// Allocates a framebuffer of `width` by `height` pixels and registers a text console on it.
+pub fn init_console(width: u32, height: u32) -> Result<(), KError> {
+    let fb = Framebuffer::new(width, height)?;
+    crate::irq::without_interrupts(|| {
+        *FRAMEBUFFER_CONSOLE.text.lock() = Some(TextConsole::new(fb, Color::GRAY, Color::BLACK));
+    });
+    console::register(&FRAMEBUFFER_CONSOLE)?;
+    Ok(())
+}
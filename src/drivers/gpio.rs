@@ -1,6 +1,34 @@
+//! GPIO pins of the BCM2837.
+//!
+//! Each of the [`NUM_PINS`] pins has a function, selecting whether it is an input, an output or
+//! driven by a peripheral, and an optional pull-up or pull-down resistor. Any pin can also detect
+//! events, which are latched in the event detect status register and raise the GPIO interrupts
+//! while they are set.
+//!
+//! Drivers get the pins they use as [`Pin`]s, claimed from the [`Pins`] singleton, whose type
+//! tracks the function of the pin. The untyped [`GPIO`] handle takes pin numbers at runtime, which
+//! are checked, returning [`GpioError::InvalidPin`] for pins that don't exist. It can reconfigure
+//! pins owned by drivers, so getting one is unsafe.
+//!
+//! Registers shared by several pins are updated while holding a lock. The set, clear, level and
+//! event status registers only affect the pins whose bits are written, so they are used without it.
+
+mod pin;
+
+pub use pin::{Alt, Input, Mode, Output, Pin, Pins};
+
+use core::{fmt, ptr};
+
 use super::{Reg32, MMIO_BASE_ADDR};
+use crate::error::{Error, ErrorKind};
+use crate::irq;
+use crate::time::{spin_for, Duration};
 
-use crate::utils::delay_cycles;
+/// Number of GPIO pins.
+pub const NUM_PINS: u8 = 54;
+
+/// Time the pull-up/down control signals must be held, 150 cycles of the slowest clock.
+const PULL_SETUP_TIME: Duration = Duration::from_micros(2);
 
 #[repr(C)]
 struct GPIOPinData {
@@ -26,66 +54,355 @@ struct GPIORegisters {
     pullup_pulldown_clocks: [Reg32; 2],
 }
 
+/// Function of a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GPIOFunc {
+    /// Input.
     Input = 0b000,
+    /// Output.
     Output = 0b001,
+    /// Alternate function 0.
     AltFn0 = 0b100,
+    /// Alternate function 1.
     AltFn1 = 0b101,
+    /// Alternate function 2.
     AltFn2 = 0b110,
+    /// Alternate function 3.
     AltFn3 = 0b111,
+    /// Alternate function 4.
     AltFn4 = 0b011,
+    /// Alternate function 5.
     AltFn5 = 0b010,
 }
 
-impl GPIORegisters {
-    const REGS_ADDR: usize = MMIO_BASE_ADDR + 0x20000;
-
-    /// # Safety
-    ///
-    /// This function doesn't garantee that there can always only be a single
-    /// mutable reference. It is possible to create multiple aliassing to the
-    /// same memory location by calling this function many times. As such, it is
-    /// marked as unsafe.
-    #[inline(always)]
-    pub unsafe fn get() -> &'static mut Self {
-        let ptr = Self::REGS_ADDR as *mut GPIORegisters;
-        &mut *ptr
+impl GPIOFunc {
+    fn from_bits(bits: u32) -> Self {
+        match bits & 0b111 {
+            0b000 => GPIOFunc::Input,
+            0b001 => GPIOFunc::Output,
+            0b100 => GPIOFunc::AltFn0,
+            0b101 => GPIOFunc::AltFn1,
+            0b110 => GPIOFunc::AltFn2,
+            0b111 => GPIOFunc::AltFn3,
+            0b011 => GPIOFunc::AltFn4,
+            _ => GPIOFunc::AltFn5,
+        }
     }
 }
 
-pub struct GPIO {
-    regs: &'static mut GPIORegisters,
+/// Pull resistor of a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pull {
+    /// No resistor, the pin floats when nothing drives it.
+    None = 0b00,
+    /// Pull-down resistor.
+    Down = 0b01,
+    /// Pull-up resistor.
+    Up = 0b10,
 }
 
-impl GPIO {
-    pub fn acquire() -> Self {
-        // FIXME: This should be thread safe.
-        unsafe {
-            GPIO {
-                regs: GPIORegisters::get(),
-            }
+/// Event a pin can detect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A rising edge, sampled with the system clock.
+    RisingEdge,
+    /// A falling edge, sampled with the system clock.
+    FallingEdge,
+    /// A rising edge, not sampled, which detects very short pulses.
+    AsyncRisingEdge,
+    /// A falling edge, not sampled, which detects very short pulses.
+    AsyncFallingEdge,
+    /// The pin is high. Stays detected as long as the pin is high, even after clearing it.
+    High,
+    /// The pin is low. Stays detected as long as the pin is low, even after clearing it.
+    Low,
+}
+
+/// Errors of the GPIO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpioError {
+    /// The pin doesn't exist.
+    InvalidPin(u8),
+    /// The pin was already claimed from [`Pins`].
+    AlreadyClaimed(u8),
+}
+
+impl fmt::Display for GpioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GpioError::InvalidPin(pin) => write!(f, "GPIO pin {} doesn't exist", pin),
+            GpioError::AlreadyClaimed(pin) => write!(f, "GPIO pin {} is already claimed", pin),
+        }
+    }
+}
+
+impl Error for GpioError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            GpioError::InvalidPin(_) => ErrorKind::InvalidArgument,
+            GpioError::AlreadyClaimed(_) => ErrorKind::AlreadyExists,
         }
     }
+}
+
+/// Checks that `pin` exists.
+fn check_pin(pin: u8) -> Result<u8, GpioError> {
+    if pin < NUM_PINS {
+        Ok(pin)
+    } else {
+        Err(GpioError::InvalidPin(pin))
+    }
+}
+
+/// The GPIO registers. They are used by every core at the same time, so no reference to them is
+/// ever created, they are only accessed through [`Register`]s.
+const REGS: *mut GPIORegisters = (MMIO_BASE_ADDR + 0x20000) as *mut GPIORegisters;
+
+/// Serializes the updates of the registers shared by several pins: the function selects, the
+/// event detect enables and the pull-up/down sequence. Taken with interrupts masked.
+static LOCK: spin::Mutex<()> = spin::Mutex::new(());
 
-    pub fn set_pin_func(&mut self, pin: u8, func: GPIOFunc) {
-        let bit: u32 = (pin as u32 * 3) % 30;
-        let reg_idx = (pin / 10) as usize;
-        let mut reg = self.regs.func_select[reg_idx].read();
+/// A GPIO register, accessed through a raw pointer. Registers shared by several pins must only be
+/// modified while holding [`LOCK`].
+#[derive(Clone, Copy)]
+struct Register(*mut Reg32);
+
+impl Register {
+    fn read(self) -> u32 {
+        // SAFETY: `Register`s are only made by `reg!`, so they point to a GPIO register, which is
+        // always mapped as device memory.
+        unsafe { ptr::read_volatile(ptr::addr_of!((*self.0).0)) }
+    }
+
+    fn write(self, val: u32) {
+        // SAFETY: As in `read`.
+        unsafe { ptr::write_volatile(ptr::addr_of_mut!((*self.0).0), val) }
+    }
+}
+
+/// Gets the [`Register`] at the place `$place` of [`GPIORegisters`], like `level.data[0]`.
+macro_rules! reg {
+    ($($place:tt)+) => {
+        // SAFETY: Only the address of the register is computed, without creating a reference.
+        Register(unsafe { ptr::addr_of_mut!((*REGS).$($place)+) })
+    };
+}
+
+/// Index of the register holding the bit of `pin` in [`GPIOPinData`].
+fn bank(pin: u8) -> usize {
+    (pin / 32) as usize
+}
+
+/// The bit of `pin` in its register of [`GPIOPinData`].
+fn bit(pin: u8) -> u32 {
+    1 << (pin % 32)
+}
+
+/// The event detect enable register for `event` holding the bit of `pin`.
+fn event_enable(event: Event, pin: u8) -> Register {
+    let bank = bank(pin);
+    match event {
+        Event::RisingEdge => reg!(rising_edge_detect_enable.data[bank]),
+        Event::FallingEdge => reg!(falling_edge_detect_enable.data[bank]),
+        Event::AsyncRisingEdge => reg!(pin_async_rising_edge_detect.data[bank]),
+        Event::AsyncFallingEdge => reg!(pin_async_falling_edge_detect.data[bank]),
+        Event::High => reg!(pin_high_detect_enable.data[bank]),
+        Event::Low => reg!(pin_low_detect_enable.data[bank]),
+    }
+}
+
+// Operations on a single pin, which must exist.
+
+fn set_func(pin: u8, func: GPIOFunc) {
+    let bit: u32 = (pin as u32 * 3) % 30;
+    let reg_idx = (pin / 10) as usize;
+    irq::without_interrupts(|| {
+        let _guard = LOCK.lock();
+        let mut reg = reg!(func_select[reg_idx]).read();
 
         // Clear the 3 bits of the function select.
         reg &= !(0b111 << bit);
         // Set the bits to the desired values.
         reg |= (func as u32) << bit;
 
-        self.regs.func_select[reg_idx].write(reg);
+        reg!(func_select[reg_idx]).write(reg);
+    })
+}
+
+fn func(pin: u8) -> GPIOFunc {
+    let reg = reg!(func_select[(pin / 10) as usize]).read();
+    GPIOFunc::from_bits(reg >> ((pin as u32 * 3) % 30))
+}
+
+fn set_pull(pin: u8, pull: Pull) {
+    irq::without_interrupts(|| {
+        let _guard = LOCK.lock();
+        // The control signal is set first, then clocked into the pin.
+        reg!(pullup_pulldown_enable).write(pull as u32);
+        spin_for(PULL_SETUP_TIME);
+        reg!(pullup_pulldown_clocks[bank(pin)]).write(bit(pin));
+        spin_for(PULL_SETUP_TIME);
+        reg!(pullup_pulldown_enable).write(0);
+        reg!(pullup_pulldown_clocks[bank(pin)]).write(0);
+    })
+}
+
+fn set_level(pin: u8, high: bool) {
+    // Writing zero to the other bits does nothing.
+    if high {
+        reg!(output_set.data[bank(pin)]).write(bit(pin));
+    } else {
+        reg!(output_clear.data[bank(pin)]).write(bit(pin));
+    }
+}
+
+fn is_high(pin: u8) -> bool {
+    reg!(level.data[bank(pin)]).read() & bit(pin) != 0
+}
+
+/// Sets or clears the bit of `pin` in `reg`, keeping the other bits. Must hold [`LOCK`].
+fn modify(reg: Register, pin: u8, set: bool) {
+    let val = reg.read();
+    reg.write(if set { val | bit(pin) } else { val & !bit(pin) });
+}
+
+fn set_event_detect(pin: u8, event: Event, enabled: bool) {
+    irq::without_interrupts(|| {
+        let _guard = LOCK.lock();
+        modify(event_enable(event, pin), pin, enabled);
+    })
+}
+
+fn disable_events(pin: u8) {
+    irq::without_interrupts(|| {
+        let _guard = LOCK.lock();
+        for event in [
+            Event::RisingEdge,
+            Event::FallingEdge,
+            Event::AsyncRisingEdge,
+            Event::AsyncFallingEdge,
+            Event::High,
+            Event::Low,
+        ] {
+            modify(event_enable(event, pin), pin, false);
+        }
+    });
+    clear_event(pin);
+}
+
+fn event_detected(pin: u8) -> bool {
+    reg!(event_detect_status.data[bank(pin)]).read() & bit(pin) != 0
+}
+
+fn clear_event(pin: u8) {
+    // Writing zero to the other bits does nothing.
+    reg!(event_detect_status.data[bank(pin)]).write(bit(pin));
+}
+
+/// Untyped handle to the GPIO, taking pin numbers at runtime.
+pub struct GPIO {
+    _private: (),
+}
+
+impl GPIO {
+    /// Gets a handle to the GPIO.
+    ///
+    /// # Safety
+    ///
+    /// The handle can change any pin, so it must not be used on pins claimed from [`Pins`].
+    pub unsafe fn acquire() -> Self {
+        GPIO { _private: () }
+    }
+
+    /// Sets the function of `pin`.
+    pub fn set_pin_func(&mut self, pin: u8, func: GPIOFunc) -> Result<(), GpioError> {
+        set_func(check_pin(pin)?, func);
+        Ok(())
+    }
+
+    /// Gets the function of `pin`.
+    pub fn pin_func(&mut self, pin: u8) -> Result<GPIOFunc, GpioError> {
+        Ok(func(check_pin(pin)?))
+    }
+
+    /// Sets the pull resistor of `pin`.
+    pub fn set_pull(&mut self, pin: u8, pull: Pull) -> Result<(), GpioError> {
+        set_pull(check_pin(pin)?, pull);
+        Ok(())
+    }
+
+    /// Drives `pin` high, if it is an output.
+    pub fn set_high(&mut self, pin: u8) -> Result<(), GpioError> {
+        set_level(check_pin(pin)?, true);
+        Ok(())
+    }
+
+    /// Drives `pin` low, if it is an output.
+    pub fn set_low(&mut self, pin: u8) -> Result<(), GpioError> {
+        set_level(check_pin(pin)?, false);
+        Ok(())
+    }
+
+    /// Drives `pin` high or low.
+    pub fn set_level(&mut self, pin: u8, high: bool) -> Result<(), GpioError> {
+        set_level(check_pin(pin)?, high);
+        Ok(())
+    }
+
+    /// Drives `pin` to the opposite of its current level.
+    pub fn toggle(&mut self, pin: u8) -> Result<(), GpioError> {
+        let pin = check_pin(pin)?;
+        set_level(pin, !is_high(pin));
+        Ok(())
+    }
+
+    /// Whether `pin` is high. Works for any function.
+    pub fn is_high(&mut self, pin: u8) -> Result<bool, GpioError> {
+        Ok(is_high(check_pin(pin)?))
+    }
+
+    /// Whether `pin` is low. Works for any function.
+    pub fn is_low(&mut self, pin: u8) -> Result<bool, GpioError> {
+        Ok(!is_high(check_pin(pin)?))
+    }
+
+    /// Enables or disables the detection of `event` on `pin`.
+    pub fn set_event_detect(
+        &mut self,
+        pin: u8,
+        event: Event,
+        enabled: bool,
+    ) -> Result<(), GpioError> {
+        set_event_detect(check_pin(pin)?, event, enabled);
+        Ok(())
+    }
+
+    /// Disables the detection of every event on `pin`, and clears its status.
+    pub fn disable_events(&mut self, pin: u8) -> Result<(), GpioError> {
+        disable_events(check_pin(pin)?);
+        Ok(())
+    }
+
+    /// Whether an enabled event was detected on `pin` since its status was cleared.
+    pub fn event_detected(&mut self, pin: u8) -> Result<bool, GpioError> {
+        Ok(event_detected(check_pin(pin)?))
+    }
+
+    /// Clears the event status of `pin`.
+    pub fn clear_event(&mut self, pin: u8) -> Result<(), GpioError> {
+        clear_event(check_pin(pin)?);
+        Ok(())
+    }
+
+    /// Gets the event status of every pin, one bit per pin.
+    pub fn detected_events(&mut self) -> u64 {
+        reg!(event_detect_status.data[0]).read() as u64
+            | (reg!(event_detect_status.data[1]).read() as u64) << 32
     }
 
-    pub fn pin_enable(&mut self, pin: u8) {
-        self.regs.pullup_pulldown_enable.write(0);
-        delay_cycles(150);
-        self.regs.pullup_pulldown_clocks[(pin / 32) as usize].write(1 << (pin % 32));
-        delay_cycles(150);
-        self.regs.pullup_pulldown_enable.write(0);
-        self.regs.pullup_pulldown_clocks[(pin / 32) as usize].write(0);
+    /// Clears the event status of the pins whose bit is set in `pins`.
+    pub fn clear_events(&mut self, pins: u64) {
+        reg!(event_detect_status.data[0]).write(pins as u32);
+        reg!(event_detect_status.data[1]).write((pins >> 32) as u32);
     }
 }
@@ -0,0 +1,211 @@
+//! Pins owned by their users, with their function tracked in their type.
+//!
+//! The [`Pins`] singleton claims pins, each at most once, directly in the function they are used
+//! in, so that claiming a pin never switches it to another function first. Converting a pin
+//! consumes it and changes its function, so a [`Pin<N, Output>`] is always an output. Drivers take
+//! the pins they need by value, like [`crate::drivers::MiniUART::init`], which wants pins 14 and
+//! 15 in alternate function 5.
+//!
+//! Once the kernel is initialized, the pins are kept with [`Pins::store`], so that they can be
+//! claimed at any time through [`Pins::with`].
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::{Event, GPIOFunc, GpioError, Pull, NUM_PINS};
+use crate::irq;
+
+/// Whether [`Pins`] was taken.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+/// The pins kept by [`Pins::store`]. Taken with interrupts masked.
+static STORED: spin::Mutex<Option<Pins>> = spin::Mutex::new(None);
+
+/// Function of a [`Pin`].
+pub trait Mode {
+    /// The value of the function select.
+    const FUNC: GPIOFunc;
+}
+
+/// The pin is an input.
+pub struct Input;
+
+/// The pin is an output.
+pub struct Output;
+
+/// The pin is driven by the peripheral of alternate function `F`.
+pub struct Alt<const F: u8>;
+
+impl Mode for Input {
+    const FUNC: GPIOFunc = GPIOFunc::Input;
+}
+
+impl Mode for Output {
+    const FUNC: GPIOFunc = GPIOFunc::Output;
+}
+
+macro_rules! alt_modes {
+    ($($f:literal => $func:ident),* $(,)?) => {
+        $(
+            impl Mode for Alt<$f> {
+                const FUNC: GPIOFunc = GPIOFunc::$func;
+            }
+        )*
+    };
+}
+
+alt_modes! {
+    0 => AltFn0,
+    1 => AltFn1,
+    2 => AltFn2,
+    3 => AltFn3,
+    4 => AltFn4,
+    5 => AltFn5,
+}
+
+/// The GPIO pins, which are claimed from it.
+pub struct Pins {
+    /// One bit per claimed pin.
+    claimed: u64,
+}
+
+impl Pins {
+    /// Takes the pins. Returns `None` if they were already taken.
+    pub fn take() -> Option<Self> {
+        if TAKEN.swap(true, Ordering::Acquire) {
+            None
+        } else {
+            Some(Pins { claimed: 0 })
+        }
+    }
+
+    /// Keeps the pins globally, so that they can be claimed at any time with [`Pins::with`].
+    pub fn store(self) {
+        irq::without_interrupts(|| *STORED.lock() = Some(self));
+    }
+
+    /// Calls `f` with the pins kept by [`Pins::store`]. Returns `None` if they weren't stored.
+    pub fn with<R>(f: impl FnOnce(&mut Pins) -> R) -> Option<R> {
+        irq::without_interrupts(|| STORED.lock().as_mut().map(f))
+    }
+
+    /// Claims pin `N` and gives it function `M`, without going through any other function. A pin
+    /// that doesn't exist fails to compile.
+    pub fn pin<const N: u8, M: Mode>(&mut self) -> Result<Pin<N, M>, GpioError> {
+        let () = Pin::<N, M>::EXISTS;
+        if self.claimed & (1 << N) != 0 {
+            return Err(GpioError::AlreadyClaimed(N));
+        }
+        self.claimed |= 1 << N;
+        super::set_func(N, M::FUNC);
+        Ok(Pin { mode: PhantomData })
+    }
+
+    /// Whether `pin` is claimed.
+    pub fn is_claimed(&self, pin: u8) -> bool {
+        pin < NUM_PINS && self.claimed & (1 << pin) != 0
+    }
+
+    /// Gives back a pin, which can be claimed again. Its function is left unchanged.
+    pub fn release<const N: u8, M: Mode>(&mut self, pin: Pin<N, M>) {
+        let _ = pin;
+        self.claimed &= !(1 << N);
+    }
+}
+
+/// Pin `N` of the GPIO, with function `M`.
+///
+/// Dropping a pin keeps it claimed. It must be given to [`Pins::release`] to be claimed again.
+pub struct Pin<const N: u8, M: Mode> {
+    mode: PhantomData<M>,
+}
+
+impl<const N: u8, M: Mode> Pin<N, M> {
+    const EXISTS: () = assert!(N < NUM_PINS, "the GPIO pin doesn't exist");
+
+    /// Number of the pin.
+    pub fn number(&self) -> u8 {
+        N
+    }
+
+    fn into_mode<T: Mode>(self) -> Pin<N, T> {
+        super::set_func(N, T::FUNC);
+        Pin { mode: PhantomData }
+    }
+
+    /// Makes the pin an input.
+    pub fn into_input(self) -> Pin<N, Input> {
+        self.into_mode()
+    }
+
+    /// Makes the pin an output, driven at its current output level.
+    pub fn into_output(self) -> Pin<N, Output> {
+        self.into_mode()
+    }
+
+    /// Gives the pin to the peripheral of alternate function `F`.
+    pub fn into_alt<const F: u8>(self) -> Pin<N, Alt<F>>
+    where
+        Alt<F>: Mode,
+    {
+        self.into_mode()
+    }
+
+    /// Sets the pull resistor of the pin.
+    pub fn set_pull(&mut self, pull: Pull) {
+        super::set_pull(N, pull);
+    }
+
+    /// Whether the pin is high. Works for any function.
+    pub fn is_high(&self) -> bool {
+        super::is_high(N)
+    }
+
+    /// Whether the pin is low. Works for any function.
+    pub fn is_low(&self) -> bool {
+        !super::is_high(N)
+    }
+}
+
+impl<const N: u8> Pin<N, Output> {
+    /// Drives the pin high.
+    pub fn set_high(&mut self) {
+        super::set_level(N, true);
+    }
+
+    /// Drives the pin low.
+    pub fn set_low(&mut self) {
+        super::set_level(N, false);
+    }
+
+    /// Drives the pin high or low.
+    pub fn set_level(&mut self, high: bool) {
+        super::set_level(N, high);
+    }
+
+    /// Drives the pin to the opposite of its current level.
+    pub fn toggle(&mut self) {
+        super::set_level(N, !super::is_high(N));
+    }
+}
+
+impl<const N: u8> Pin<N, Input> {
+    /// Enables or disables the detection of `event`.
+    pub fn set_event_detect(&mut self, event: Event, enabled: bool) {
+        super::set_event_detect(N, event, enabled);
+    }
+
+    /// Disables the detection of every event, and clears the event status.
+    pub fn disable_events(&mut self) {
+        super::disable_events(N);
+    }
+
+    /// Whether an enabled event was detected since the event status was cleared.
+    pub fn event_detected(&self) -> bool {
+        super::event_detected(N)
+    }
+
+    /// Clears the event status.
+    pub fn clear_event(&mut self) {
+        super::clear_event(N);
+    }
+}
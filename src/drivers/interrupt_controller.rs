@@ -0,0 +1,59 @@
+//! BCM2835 interrupt controller, which handles the interrupts of the GPU peripherals.
+//!
+//! The enable and disable registers are write-one-to-set, so none of the functions here need a
+//! lock, and they are safe to use from interrupt handlers.
+
+use super::{Reg32, MMIO_BASE_ADDR};
+
+/// Number of peripheral interrupts.
+pub const NUM_PERIPHERAL_IRQS: usize = 64;
+
+#[repr(C)]
+struct InterruptControllerRegisters {
+    basic_pending: Reg32,
+    pending: [Reg32; 2],
+    fiq_control: Reg32,
+    enable: [Reg32; 2],
+    enable_basic: Reg32,
+    disable: [Reg32; 2],
+    disable_basic: Reg32,
+}
+
+impl InterruptControllerRegisters {
+    const REGS_ADDR: usize = MMIO_BASE_ADDR + 0xB200;
+
+    /// # Safety
+    ///
+    /// Every reference returned aliases the same registers, so only registers that don't need
+    /// exclusive access may be used through it.
+    #[inline(always)]
+    unsafe fn get() -> &'static mut Self {
+        &mut *(Self::REGS_ADDR as *mut Self)
+    }
+}
+
+/// Enables the peripheral interrupt `irq`.
+pub fn enable(irq: u8) {
+    let regs = unsafe { InterruptControllerRegisters::get() };
+    regs.enable[irq as usize / 32].write(1 << (irq % 32));
+}
+
+/// Disables the peripheral interrupt `irq`.
+pub fn disable(irq: u8) {
+    let regs = unsafe { InterruptControllerRegisters::get() };
+    regs.disable[irq as usize / 32].write(1 << (irq % 32));
+}
+
+/// Disables every peripheral interrupt, as well as the ARM specific basic interrupts.
+pub fn disable_all() {
+    let regs = unsafe { InterruptControllerRegisters::get() };
+    regs.disable[0].write(u32::MAX);
+    regs.disable[1].write(u32::MAX);
+    regs.disable_basic.write(u32::MAX);
+}
+
+/// Gets the pending peripheral interrupts, one bit per interrupt.
+pub fn pending() -> u64 {
+    let regs = unsafe { InterruptControllerRegisters::get() };
+    regs.pending[0].read() as u64 | (regs.pending[1].read() as u64) << 32
+}
@@ -0,0 +1,135 @@
+//! Interrupt routing of the ARM local peripherals block of the BCM2837 (QA7).
+//!
+//! Each core has its own interrupt source register and its own controls for the generic timer and
+//! mailbox interrupts. The peripheral interrupts of [`super::interrupt_controller`] are routed to a
+//! single core, which sees them as [`LOCAL_IRQ_GPU`].
+//!
+//! The block is outside of the peripherals mapped by the MMU at boot, so [`init`] must be called
+//! before using any other function.
+
+use super::Reg32;
+use crate::memory::mmu::{self, Attributes, MapError};
+
+/// Address of the ARM local peripherals.
+pub const LOCAL_BASE_ADDR: usize = 0x4000_0000;
+
+/// Bit of the interrupt source register set when a peripheral interrupt is pending.
+pub const LOCAL_IRQ_GPU: u32 = 8;
+
+#[repr(C)]
+struct LocalRegisters {
+    control: Reg32,
+    _reserved0: Reg32,
+    core_timer_prescaler: Reg32,
+    gpu_irq_routing: Reg32,
+    pmu_irq_set: Reg32,
+    pmu_irq_clear: Reg32,
+    _reserved1: Reg32,
+    core_timer_low: Reg32,
+    core_timer_high: Reg32,
+    local_irq_routing: Reg32,
+    _reserved2: Reg32,
+    axi_counters: Reg32,
+    axi_irq: Reg32,
+    local_timer_control: Reg32,
+    local_timer_reload: Reg32,
+    _reserved3: Reg32,
+    timer_irq_control: [Reg32; 4],
+    mailbox_irq_control: [Reg32; 4],
+    irq_source: [Reg32; 4],
+    fiq_source: [Reg32; 4],
+    mailbox_set: [[Reg32; 4]; 4],
+    mailbox_clear: [[Reg32; 4]; 4],
+}
+
+impl LocalRegisters {
+    /// # Safety
+    ///
+    /// Every reference returned aliases the same registers. Registers shared between cores may
+    /// only be written by the boot core during initialization, and the per core control registers
+    /// may only be modified by their own core with interrupts masked.
+    #[inline(always)]
+    unsafe fn get() -> &'static mut Self {
+        &mut *(LOCAL_BASE_ADDR as *mut Self)
+    }
+}
+
+/// The generic timers of each core, in the order of their bits in the timer interrupt control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CoreTimer {
+    /// Secure physical timer.
+    PhysicalSecure = 0,
+    /// Non-secure physical timer.
+    PhysicalNonSecure = 1,
+    /// Hypervisor physical timer.
+    Hypervisor = 2,
+    /// Virtual timer.
+    Virtual = 3,
+}
+
+/// Maps the ARM local peripherals as device memory. Must be called by the boot core before the
+/// other cores are started.
+pub fn init() -> Result<(), MapError> {
+    mmu::map(
+        LOCAL_BASE_ADDR,
+        LOCAL_BASE_ADDR,
+        mmu::PAGE_SIZE,
+        Attributes::DEVICE,
+    )
+}
+
+/// Routes the peripheral interrupts to `cpu`.
+pub fn route_gpu_irqs(cpu: usize) {
+    let regs = unsafe { LocalRegisters::get() };
+    regs.gpu_irq_routing.write(cpu as u32 & 0b11);
+}
+
+/// Gets the pending interrupts of `cpu`, one bit per source.
+pub fn irq_source(cpu: usize) -> u32 {
+    let regs = unsafe { LocalRegisters::get() };
+    regs.irq_source[cpu].read()
+}
+
+/// Enables or disables the interrupt of one of the generic timers of `cpu`.
+///
+/// # Safety
+///
+/// Must be called on `cpu` itself with interrupts masked.
+pub unsafe fn set_timer_irq(cpu: usize, timer: CoreTimer, enabled: bool) {
+    let regs = LocalRegisters::get();
+    let val = regs.timer_irq_control[cpu].read();
+    let bit = 1 << timer as u32;
+    regs.timer_irq_control[cpu].write(if enabled { val | bit } else { val & !bit });
+}
+
+/// Enables or disables the interrupt of one of the mailboxes of `cpu`.
+///
+/// # Safety
+///
+/// Must be called on `cpu` itself with interrupts masked.
+pub unsafe fn set_mailbox_irq(cpu: usize, mailbox: usize, enabled: bool) {
+    let regs = LocalRegisters::get();
+    let val = regs.mailbox_irq_control[cpu].read();
+    let bit = 1 << mailbox;
+    regs.mailbox_irq_control[cpu].write(if enabled { val | bit } else { val & !bit });
+}
+
+/// Sets `bits` in the mailbox `mailbox` of `cpu`, which raises an interrupt on that core if its
+/// mailbox interrupt is enabled.
+pub fn send_mailbox(cpu: usize, mailbox: usize, bits: u32) {
+    let regs = unsafe { LocalRegisters::get() };
+    regs.mailbox_set[cpu][mailbox].write(bits);
+}
+
+/// Reads the mailbox `mailbox` of `cpu`.
+pub fn read_mailbox(cpu: usize, mailbox: usize) -> u32 {
+    let regs = unsafe { LocalRegisters::get() };
+    regs.mailbox_clear[cpu][mailbox].read()
+}
+
+/// Clears `bits` in the mailbox `mailbox` of `cpu`.
+pub fn clear_mailbox(cpu: usize, mailbox: usize, bits: u32) {
+    let regs = unsafe { LocalRegisters::get() };
+    regs.mailbox_clear[cpu][mailbox].write(bits);
+}
@@ -0,0 +1,447 @@
+//! VideoCore mailbox, the channel used to ask the firmware about the board and to configure what
+//! the VideoCore controls.
+//!
+//! Messages are exchanged through the property channel: the ARM writes the bus address of a 16 byte
+//! aligned buffer to mailbox 1, the firmware replaces the requests in the buffer with its responses,
+//! and writes the address back to mailbox 0. A message is a list of tags, each one a request with
+//! room for its response. [`Message`] builds them from [`Tag`]s, which describe the request and
+//! response of each tag as plain structs.
+//!
+//! The VideoCore doesn't go through the caches of the ARM, so the buffer is cleaned from them
+//! before it is sent, and invalidated before the response is read. Message buffers are cache line
+//! aligned for that. The mailbox is only used by one core at a time, serialized by [`LOCK`].
+
+use core::{
+    fmt,
+    marker::PhantomData,
+    mem::{self, size_of},
+    ptr,
+};
+
+use super::{Reg32, MMIO_BASE_ADDR};
+use crate::error::{Error, ErrorKind};
+use crate::fdt::Region;
+use crate::irq;
+use crate::memory;
+use crate::time::{Duration, Instant};
+
+/// Channel of the property tags, from the ARM to the VideoCore.
+pub const PROPERTY_CHANNEL: u8 = 8;
+/// Size in words of the buffer of a [`Message`].
+pub const MESSAGE_WORDS: usize = 256;
+
+/// How long the firmware has to answer.
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(100);
+
+const STATUS_FULL: u32 = 1 << 31;
+const STATUS_EMPTY: u32 = 1 << 30;
+/// Alias of the memory seen by the VideoCore that bypasses its L2 cache.
+const BUS_ADDRESS_ALIAS: u32 = 0xc000_0000;
+
+const CODE_REQUEST: u32 = 0;
+const CODE_SUCCESS: u32 = 0x8000_0000;
+const CODE_TAG_RESPONSE: u32 = 1 << 31;
+const END_TAG: u32 = 0;
+/// Words of a tag before its value: the id, the size of the value and the request/response code.
+const TAG_HEADER_WORDS: usize = 3;
+
+#[repr(C)]
+struct MailboxRegisters {
+    /// Mailbox 0, from the VideoCore to the ARM.
+    read: Reg32,
+    _reserved0: [Reg32; 3],
+    peek: Reg32,
+    sender: Reg32,
+    status: Reg32,
+    config: Reg32,
+    /// Mailbox 1, from the ARM to the VideoCore.
+    write: Reg32,
+    _reserved1: [Reg32; 5],
+    write_status: Reg32,
+}
+
+impl MailboxRegisters {
+    const REGS_ADDR: usize = MMIO_BASE_ADDR + 0xB880;
+
+    /// # Safety
+    ///
+    /// Every reference returned aliases the same registers. They must only be used while holding
+    /// [`LOCK`].
+    #[inline(always)]
+    unsafe fn get() -> &'static mut Self {
+        &mut *(Self::REGS_ADDR as *mut Self)
+    }
+}
+
+/// Serializes the use of the mailbox, which must be taken with interrupts masked.
+static LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Errors of the mailbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MailboxError {
+    /// The firmware didn't answer in time.
+    Timeout,
+    /// The tags don't fit in the message.
+    MessageTooLong,
+    /// The firmware couldn't parse the message.
+    RequestFailed,
+    /// The firmware didn't answer a tag, which usually means that it doesn't know it.
+    TagNotAnswered(u32),
+    /// The response of a tag is shorter than expected.
+    ResponseTooShort(u32),
+}
+
+impl fmt::Display for MailboxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MailboxError::Timeout => f.write_str("the firmware did not answer the mailbox"),
+            MailboxError::MessageTooLong => f.write_str("mailbox message is too long"),
+            MailboxError::RequestFailed => f.write_str("the firmware rejected the mailbox message"),
+            MailboxError::TagNotAnswered(id) => {
+                write!(f, "the firmware did not answer tag {:#010x}", id)
+            }
+            MailboxError::ResponseTooShort(id) => {
+                write!(f, "response to tag {:#010x} is too short", id)
+            }
+        }
+    }
+}
+
+impl Error for MailboxError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            MailboxError::Timeout => ErrorKind::Timeout,
+            MailboxError::MessageTooLong => ErrorKind::InvalidArgument,
+            MailboxError::RequestFailed | MailboxError::ResponseTooShort(_) => {
+                ErrorKind::DeviceError
+            }
+            MailboxError::TagNotAnswered(_) => ErrorKind::Unsupported,
+        }
+    }
+}
+
+/// A property tag.
+///
+/// # Safety
+///
+/// `Request` and `Response` must be `#[repr(C)]` structs of `u32`s, or `u32`s, with the layout the
+/// firmware expects, since responses are read from whatever the firmware wrote.
+pub unsafe trait Tag {
+    /// Identifier of the tag.
+    const ID: u32;
+    /// Value sent to the firmware.
+    type Request: Copy;
+    /// Value answered by the firmware.
+    type Response: Copy;
+}
+
+/// Declares a [`Tag`].
+macro_rules! tags {
+    ($($(#[$attr:meta])* $name:ident = $id:literal: $req:ty => $resp:ty;)*) => {$(
+        $(#[$attr])*
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name;
+
+        // SAFETY: The requests and responses are `u32`s or structs of them.
+        unsafe impl Tag for $name {
+            const ID: u32 = $id;
+            type Request = $req;
+            type Response = $resp;
+        }
+    )*};
+}
+
+/// A range of memory, in the layout used by the firmware.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRange {
+    /// Start address.
+    pub base: u32,
+    /// Size in bytes.
+    pub size: u32,
+}
+
+/// A value of some part of the board, like a temperature or a clock rate.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdValue {
+    /// Identifier of the part.
+    pub id: u32,
+    /// The value.
+    pub value: u32,
+}
+
+/// Request of [`SetClockRate`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockRateRequest {
+    /// Identifier of the clock.
+    pub id: u32,
+    /// Rate in Hz.
+    pub rate: u32,
+    /// Whether to leave the turbo settings alone, which the firmware otherwise changes when the
+    /// ARM clock is set above its default.
+    pub skip_setting_turbo: u32,
+}
+
+/// A size in pixels.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+}
+
+/// A position in pixels.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Offset {
+    /// Horizontal position in pixels.
+    pub x: u32,
+    /// Vertical position in pixels.
+    pub y: u32,
+}
+
+tags! {
+    /// Revision of the firmware.
+    GetFirmwareRevision = 0x0000_0001: () => u32;
+    /// Revision code of the board.
+    GetBoardRevision = 0x0001_0002: () => u32;
+    /// Serial number of the board.
+    GetBoardSerial = 0x0001_0004: () => [u32; 2];
+    /// Memory given to the ARM.
+    GetArmMemory = 0x0001_0005: () => MemoryRange;
+    /// Memory kept by the VideoCore.
+    GetVcMemory = 0x0001_0006: () => MemoryRange;
+    /// Whether a clock is on (bit 0) and exists (bit 1 clear).
+    GetClockState = 0x0003_0001: u32 => IdValue;
+    /// Rate of a clock in Hz, as set, zero if it doesn't exist.
+    GetClockRate = 0x0003_0002: u32 => IdValue;
+    /// Highest rate of a clock in Hz.
+    GetMaxClockRate = 0x0003_0004: u32 => IdValue;
+    /// Lowest rate of a clock in Hz.
+    GetMinClockRate = 0x0003_0007: u32 => IdValue;
+    /// Rate of a clock in Hz, as measured.
+    GetClockRateMeasured = 0x0003_0047: u32 => IdValue;
+    /// Sets the rate of a clock, and answers the rate it got.
+    SetClockRate = 0x0003_8002: ClockRateRequest => IdValue;
+    /// Allocates the framebuffer with the given alignment, answering its bus address and size.
+    AllocateBuffer = 0x0004_0001: u32 => MemoryRange;
+    /// Releases the framebuffer.
+    ReleaseBuffer = 0x0004_8001: () => ();
+    /// Sets the size of the display.
+    SetPhysicalSize = 0x0004_8003: Size => Size;
+    /// Sets the size of the framebuffer, which may be larger than the display.
+    SetVirtualSize = 0x0004_8004: Size => Size;
+    /// Sets the bits per pixel.
+    SetDepth = 0x0004_8005: u32 => u32;
+    /// Sets the order of the color components, 0 for BGR and 1 for RGB.
+    SetPixelOrder = 0x0004_8006: u32 => u32;
+    /// Bytes per row of the framebuffer.
+    GetPitch = 0x0004_0008: () => u32;
+    /// Sets the part of the framebuffer shown on the display.
+    SetVirtualOffset = 0x0004_8009: Offset => Offset;
+    /// Temperature of a sensor in thousandths of degree Celsius.
+    GetTemperature = 0x0003_0006: u32 => IdValue;
+    /// Temperature in thousandths of degree Celsius at which the clocks are throttled.
+    GetMaxTemperature = 0x0003_000a: u32 => IdValue;
+}
+
+/// Identifier of the only temperature sensor, of the SoC.
+pub const SOC_TEMPERATURE: u32 = 0;
+
+/// The buffer of a message. It must be 16 byte aligned for the mailbox, and is aligned to cache
+/// lines so that cleaning and invalidating it doesn't touch anything else.
+#[repr(C, align(64))]
+struct Buffer([u32; MESSAGE_WORDS]);
+
+/// A property message, built by pushing tags to it. Their responses can be read after it is sent.
+pub struct Message {
+    buffer: Buffer,
+    /// Words used by the header and the tags.
+    len: usize,
+}
+
+/// Where the response of a tag pushed to a [`Message`] is.
+pub struct TagSlot<T: Tag> {
+    /// Index of the first word of the tag.
+    offset: usize,
+    _marker: PhantomData<T>,
+}
+
+/// Size in bytes of the value of `T`, which has room for both its request and its response.
+fn value_size<T: Tag>() -> usize {
+    let size = size_of::<T::Request>().max(size_of::<T::Response>());
+    memory::align_up(size, size_of::<u32>())
+}
+
+impl Message {
+    /// Creates an empty message.
+    pub fn new() -> Self {
+        Message {
+            buffer: Buffer([0; MESSAGE_WORDS]),
+            len: 2,
+        }
+    }
+
+    /// Adds the tag `T` to the message, with its request.
+    pub fn push<T: Tag>(&mut self, request: T::Request) -> Result<TagSlot<T>, MailboxError> {
+        let value_words = value_size::<T>() / size_of::<u32>();
+        let offset = self.len;
+        // The end tag must still fit after this one.
+        if offset + TAG_HEADER_WORDS + value_words + 1 > MESSAGE_WORDS {
+            return Err(MailboxError::MessageTooLong);
+        }
+        let words = &mut self.buffer.0;
+        words[offset] = T::ID;
+        words[offset + 1] = value_size::<T>() as u32;
+        words[offset + 2] = CODE_REQUEST;
+        words[offset + TAG_HEADER_WORDS..offset + TAG_HEADER_WORDS + value_words].fill(0);
+        // SAFETY: There is room for the value, checked above.
+        unsafe {
+            let value = words.as_mut_ptr().add(offset + TAG_HEADER_WORDS);
+            ptr::write_unaligned(value as *mut T::Request, request);
+        }
+        self.len += TAG_HEADER_WORDS + value_words;
+        Ok(TagSlot {
+            offset,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Sends the message and waits for the response.
+    pub fn send(&mut self) -> Result<(), MailboxError> {
+        let words = &mut self.buffer.0;
+        words[self.len] = END_TAG;
+        // The size must be a multiple of 16 bytes.
+        let size = memory::align_up((self.len + 1) * size_of::<u32>(), 16);
+        words[self.len + 1..size / size_of::<u32>()].fill(0);
+        words[0] = size as u32;
+        words[1] = CODE_REQUEST;
+
+        let addr = words.as_ptr() as usize;
+        memory::clean_dcache_range(addr, mem::size_of::<Buffer>());
+        let res = call(PROPERTY_CHANNEL, addr as u32 | BUS_ADDRESS_ALIAS);
+        // SAFETY: `Buffer` is aligned to and a multiple of the cache line size, and we have the
+        // only reference to it.
+        unsafe { memory::invalidate_dcache_range(addr, mem::size_of::<Buffer>()) };
+        res?;
+
+        // SAFETY: The firmware wrote to the buffer behind the back of the compiler.
+        match unsafe { ptr::read_volatile(&self.buffer.0[1]) } {
+            CODE_SUCCESS => Ok(()),
+            _ => Err(MailboxError::RequestFailed),
+        }
+    }
+
+    /// Gets the response to the tag in `slot`, once the message was sent.
+    pub fn response<T: Tag>(&self, slot: &TagSlot<T>) -> Result<T::Response, MailboxError> {
+        let words = &self.buffer.0;
+        // SAFETY: The firmware wrote to the buffer behind the back of the compiler.
+        let code = unsafe { ptr::read_volatile(&words[slot.offset + 2]) };
+        if code & CODE_TAG_RESPONSE == 0 {
+            return Err(MailboxError::TagNotAnswered(T::ID));
+        }
+        if ((code & !CODE_TAG_RESPONSE) as usize) < size_of::<T::Response>() {
+            return Err(MailboxError::ResponseTooShort(T::ID));
+        }
+        // SAFETY: The value has room for the response, and `Tag` guarantees that any bytes are a
+        // valid response.
+        unsafe {
+            let value = words.as_ptr().add(slot.offset + TAG_HEADER_WORDS);
+            Ok(ptr::read_volatile(value as *const T::Response))
+        }
+    }
+}
+
+impl Default for Message {
+    fn default() -> Self {
+        Message::new()
+    }
+}
+
+/// Sends a message with the single tag `T` and gets its response.
+pub fn property<T: Tag>(request: T::Request) -> Result<T::Response, MailboxError> {
+    let mut message = Message::new();
+    let slot = message.push::<T>(request)?;
+    message.send()?;
+    message.response(&slot)
+}
+
+/// Writes `data` to `channel` and waits for the answer of the VideoCore on the same channel. The
+/// low 4 bits of `data` must be zero, since they carry the channel.
+pub fn call(channel: u8, data: u32) -> Result<u32, MailboxError> {
+    irq::without_interrupts(|| {
+        let _guard = LOCK.lock();
+        // SAFETY: The lock is held.
+        let regs = unsafe { MailboxRegisters::get() };
+        let start = Instant::now();
+        let wait = |reg: &mut Reg32, flag: u32| {
+            while reg.read() & flag != 0 {
+                if start.elapsed() > RESPONSE_TIMEOUT {
+                    return Err(MailboxError::Timeout);
+                }
+                core::hint::spin_loop();
+            }
+            Ok(())
+        };
+
+        wait(&mut regs.write_status, STATUS_FULL)?;
+        regs.write.write(data & !0xf | channel as u32);
+        loop {
+            wait(&mut regs.status, STATUS_EMPTY)?;
+            let answer = regs.read.read();
+            // Answers on other channels are not for us, and dropped.
+            if answer & 0xf == channel as u32 {
+                return Ok(answer & !0xf);
+            }
+        }
+    })
+}
+
+/// Gets the revision of the firmware.
+pub fn firmware_revision() -> Result<u32, MailboxError> {
+    property::<GetFirmwareRevision>(())
+}
+
+/// Gets the revision code of the board.
+pub fn board_revision() -> Result<u32, MailboxError> {
+    property::<GetBoardRevision>(())
+}
+
+/// Gets the serial number of the board.
+pub fn board_serial() -> Result<u64, MailboxError> {
+    let [low, high] = property::<GetBoardSerial>(())?;
+    Ok((high as u64) << 32 | low as u64)
+}
+
+/// Gets the memory given to the ARM.
+pub fn arm_memory() -> Result<Region, MailboxError> {
+    let range = property::<GetArmMemory>(())?;
+    Ok(Region {
+        base: range.base as u64,
+        size: range.size as u64,
+    })
+}
+
+/// Gets the memory kept by the VideoCore.
+pub fn vc_memory() -> Result<Region, MailboxError> {
+    let range = property::<GetVcMemory>(())?;
+    Ok(Region {
+        base: range.base as u64,
+        size: range.size as u64,
+    })
+}
+
+/// Gets the temperature of the SoC in thousandths of degree Celsius.
+pub fn temperature() -> Result<u32, MailboxError> {
+    Ok(property::<GetTemperature>(SOC_TEMPERATURE)?.value)
+}
+
+/// Gets the temperature of the SoC in thousandths of degree Celsius at which the clocks are
+/// throttled.
+pub fn max_temperature() -> Result<u32, MailboxError> {
+    Ok(property::<GetMaxTemperature>(SOC_TEMPERATURE)?.value)
+}
@@ -1,12 +1,39 @@
+//! The Mini UART of the auxiliary peripherals.
+//!
+//! Until [`init_irq`] is called, the UART is used by polling its status register. Afterwards the
+//! receive interrupt fills [`RX_BUFFER`] and the transmit interrupt drains [`TX_BUFFER`], so neither
+//! sending nor receiving spins on the hardware while holding [`LOCK`]. Senders are serialized by
+//! [`LOCK`] and receivers by [`RX_LOCK`], which keeps each buffer single-producer single-consumer.
+
 use core::{
     fmt::{self, Write},
-    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+    sync::atomic::{self, AtomicBool, AtomicPtr, AtomicUsize, Ordering},
 };
 
 use super::{
-    gpio::{GPIOFunc, GPIO},
+    clocks::{self, Clock},
+    gpio::{Alt, Pin, Pull},
+    mailbox::MailboxError,
     Reg32, MMIO_BASE_ADDR,
 };
+use crate::console::Console;
+use crate::error::{Error, ErrorKind};
+use crate::irq::{self, IrqError, IrqSource};
+use crate::utils::ring_buffer::RingBuffer;
+
+const LSR_DATA_READY: u32 = 1 << 0;
+const LSR_RX_OVERRUN: u32 = 1 << 1;
+const LSR_TX_EMPTY: u32 = 1 << 5;
+
+/// Bits 2 and 3 are documented as unused, but the interrupts don't fire without them.
+const IER_RX: u32 = 1 << 0 | 0b1100;
+const IER_TX: u32 = 1 << 1;
+
+/// Bit of the auxiliary interrupt status register for the Mini UART.
+const AUX_IRQ_MINI_UART: u32 = 1 << 0;
+
+const RX_BUFFER_SIZE: usize = 1024;
+const TX_BUFFER_SIZE: usize = 4096;
 
 #[repr(C)]
 struct MiniUARTRegisters {
@@ -35,6 +62,86 @@ impl MiniUARTRegisters {
     }
 }
 
+/// Bytes received by the interrupt handler and not read yet.
+static RX_BUFFER: RingBuffer<RX_BUFFER_SIZE> = RingBuffer::new();
+/// Bytes waiting to be written to the UART by the interrupt handler.
+static TX_BUFFER: RingBuffer<TX_BUFFER_SIZE> = RingBuffer::new();
+
+/// Serializes the consumers of [`RX_BUFFER`], as well as polling reads.
+static RX_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+/// Held by whoever is writing from [`TX_BUFFER`] to the UART, which is normally the interrupt
+/// handler, but can also be a sender with interrupts masked.
+static TX_DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the buffers are used, set by [`init_irq`].
+static IRQ_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Number of received bytes dropped because [`RX_BUFFER`] or the UART receive FIFO was full.
+static RX_OVERFLOWS: AtomicUsize = AtomicUsize::new(0);
+
+/// Gets the registers without going through [`LOCK`]. Only used for the registers touched by the
+/// interrupt handler, which are coordinated through the buffers.
+#[inline(always)]
+fn regs() -> &'static mut MiniUARTRegisters {
+    unsafe { &mut *MiniUARTRegisters::get() }
+}
+
+/// Errors of the Mini UART.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiniUartError {
+    /// The core clock, which drives the UART, couldn't be queried.
+    Clock(MailboxError),
+    /// The baud rate can't be generated from the core clock.
+    InvalidBaudRate,
+}
+
+impl fmt::Display for MiniUartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MiniUartError::Clock(_) => "failed to get the core clock rate",
+            MiniUartError::InvalidBaudRate => "invalid baud rate",
+        })
+    }
+}
+
+impl Error for MiniUartError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            MiniUartError::Clock(e) => e.kind(),
+            MiniUartError::InvalidBaudRate => ErrorKind::InvalidArgument,
+        }
+    }
+
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MiniUartError::Clock(e) => Some(e),
+            MiniUartError::InvalidBaudRate => None,
+        }
+    }
+}
+
+/// Baud rate set by [`MiniUART::init_with_baud`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BaudRate {
+    /// The baud rate asked for.
+    pub requested: u32,
+    /// The closest baud rate the divisor gives with the current core clock.
+    pub actual: u32,
+}
+
+impl BaudRate {
+    /// Difference between the actual and requested baud rates, in parts per million of the
+    /// requested one. UARTs usually tolerate a few percent.
+    pub fn error_ppm(&self) -> i64 {
+        (self.actual as i64 - self.requested as i64) * 1_000_000 / self.requested as i64
+    }
+}
+
+/// The pin the Mini UART sends on.
+pub type TxPin = Pin<14, Alt<5>>;
+/// The pin the Mini UART receives on.
+pub type RxPin = Pin<15, Alt<5>>;
+
 /// Global Mini UART lock. When the value inside the mutex is `None` it means that the Mini UART
 /// was not setup.
 static LOCK: spin::Mutex<Option<&'static mut MiniUARTRegisters>> = spin::Mutex::new(None);
@@ -45,8 +152,8 @@ pub struct MiniUART {
 }
 
 impl MiniUART {
-    const TX_PIN: u8 = 14;
-    const RX_PIN: u8 = 15;
+    /// Divisor for 115200 baud with a 250 MHz core clock, the default of the firmware.
+    pub const DEFAULT_BAUD_DIVISOR: u16 = 270;
 
     /// Acquires exclusively the Mini UART.
     ///
@@ -62,9 +169,10 @@ impl MiniUART {
         MiniUART::acquire().guard.is_some()
     }
 
-    /// Initializes the Mini UART with the default baud rate of ~115200 @ 250 MHz
-    pub fn init_default(&mut self, gpio: &mut GPIO) {
-        self.init(gpio, 270);
+    /// Initializes the Mini UART with [`MiniUART::DEFAULT_BAUD_DIVISOR`]. Prefer
+    /// [`MiniUART::init_with_baud`], which uses the actual clock.
+    pub fn init_default(&mut self, tx: TxPin, rx: RxPin) {
+        self.init(tx, rx, Self::DEFAULT_BAUD_DIVISOR);
     }
 
     /// Initializes the Mini UART. The baud rate divisor is used to calculate the baud rate of the
@@ -77,12 +185,11 @@ impl MiniUART {
     /// baudrate = ---------------------------
     ///             8 * (baudrate_divisor + 1)
     /// ```
-    pub fn init(&mut self, gpio: &mut GPIO, baud_divisor: u16) {
-        gpio.set_pin_func(Self::TX_PIN, GPIOFunc::AltFn5);
-        gpio.set_pin_func(Self::RX_PIN, GPIOFunc::AltFn5);
-
-        gpio.pin_enable(Self::TX_PIN);
-        gpio.pin_enable(Self::RX_PIN);
+    ///
+    /// The pins are kept by the Mini UART, and stay claimed.
+    pub fn init(&mut self, mut tx: TxPin, mut rx: RxPin, baud_divisor: u16) {
+        tx.set_pull(Pull::None);
+        rx.set_pull(Pull::None);
 
         // SAFETY: We are assuming that the MMIO address is correct and that the compiler won't try
         // to do some funny things with the reference.
@@ -100,34 +207,98 @@ impl MiniUART {
         self.guard.replace(regs);
     }
 
-    /// Sends a single byte through the UART. Spins while there is no space in the UART send
-    /// buffer.
-    pub fn send(&mut self, byte: u8) {
+    /// Initializes the Mini UART with the divisor closest to `baud_rate` for the current core
+    /// clock, queried through the mailbox. See [`MiniUART::init`].
+    ///
+    /// The pins are dropped, but stay claimed, if the divisor can't be computed. Use
+    /// [`MiniUART::baud_divisor`] first to keep them.
+    pub fn init_with_baud(
+        &mut self,
+        tx: TxPin,
+        rx: RxPin,
+        baud_rate: u32,
+    ) -> Result<BaudRate, MiniUartError> {
+        let (divisor, baud) = Self::baud_divisor(baud_rate)?;
+        self.init(tx, rx, divisor);
+        Ok(baud)
+    }
+
+    /// Computes the divisor closest to `baud_rate` for the current core clock, queried through
+    /// the mailbox, and the baud rate it gives.
+    pub fn baud_divisor(baud_rate: u32) -> Result<(u16, BaudRate), MiniUartError> {
+        let clock = clocks::rate(Clock::Core).map_err(MiniUartError::Clock)? as u64;
+        let baud = baud_rate as u64;
+        if baud == 0 {
+            return Err(MiniUartError::InvalidBaudRate);
+        }
+        // Rounds `clock / (8 * baud)` to the nearest integer.
+        let divisor = (clock + 4 * baud) / (8 * baud);
+        if !(1..=u16::MAX as u64 + 1).contains(&divisor) {
+            return Err(MiniUartError::InvalidBaudRate);
+        }
+        let baud = BaudRate {
+            requested: baud_rate,
+            actual: (clock / (8 * divisor)) as u32,
+        };
+        Ok(((divisor - 1) as u16, baud))
+    }
+
+    /// Tries to send a single byte without blocking, returning it back if there is no space for
+    /// it.
+    pub fn try_send(&mut self, byte: u8) -> Result<(), u8> {
         let regs = self
             .guard
             .as_mut()
             .expect("Mini UART is not setup while trying to send data");
 
-        while regs.lsr.read() & 0x20 == 0 {
-            cortex_a::asm::nop();
+        if !IRQ_MODE.load(Ordering::Acquire) {
+            if regs.lsr.read() & LSR_TX_EMPTY == 0 {
+                return Err(byte);
+            }
+            regs.io.write(byte as u32);
+            return Ok(());
         }
 
-        regs.io.write(byte as u32);
+        // SAFETY: Senders are serialized by `LOCK`.
+        unsafe { TX_BUFFER.push(byte)? };
+        atomic::fence(Ordering::SeqCst);
+        regs.ier.write(IER_RX | IER_TX);
+        // Nothing drains the buffer while interrupts are masked on this core, which could be the
+        // one receiving the UART interrupt.
+        if !irq::are_enabled() {
+            drain_tx();
+        }
+        Ok(())
     }
 
-    /// Blocks until a byte is received through the UART. This function uses a spin lock to
-    /// implement the blocking.
-    pub fn recv(&mut self) -> u8 {
-        let regs = self
-            .guard
-            .as_mut()
-            .expect("Mini UART is not setup while trying to receive data");
+    /// Sends a single byte through the UART. Blocks while there is no space for it.
+    pub fn send(&mut self, byte: u8) {
+        while self.try_send(byte).is_err() {
+            if IRQ_MODE.load(Ordering::Acquire) && !irq::are_enabled() {
+                drain_tx();
+            }
+            core::hint::spin_loop();
+        }
+    }
 
-        while regs.lsr.read() & 0x1 == 0 {
-            cortex_a::asm::nop();
+    /// Blocks until every byte sent was written to the UART.
+    pub fn flush(&mut self) {
+        while IRQ_MODE.load(Ordering::Acquire) && !TX_BUFFER.is_empty() {
+            if !irq::are_enabled() {
+                drain_tx();
+            }
+            core::hint::spin_loop();
         }
+    }
 
-        (regs.io.read() & 0xff) as u8
+    /// Blocks until a byte is received through the UART. See [`mu_recv`].
+    pub fn recv(&mut self) -> u8 {
+        mu_recv()
+    }
+
+    /// Receives a byte if one is available. See [`mu_try_recv`].
+    pub fn try_recv(&mut self) -> Option<u8> {
+        mu_try_recv()
     }
 
     /// Writes a buffer of bytes to the UART.
@@ -145,14 +316,180 @@ impl core::fmt::Write for MiniUART {
     }
 }
 
+/// The Mini UART as a [`Console`].
+pub struct MiniUartConsole;
+
+/// The Mini UART console, to be registered once the Mini UART is setup.
+pub static MINI_UART_CONSOLE: MiniUartConsole = MiniUartConsole;
+
+impl Console for MiniUartConsole {
+    fn name(&self) -> &'static str {
+        "mini-uart"
+    }
+
+    fn write_bytes(&self, buf: &[u8]) {
+        MiniUART::acquire().write(buf);
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        mu_try_recv()
+    }
+
+    fn flush(&self) {
+        mu_flush();
+    }
+
+    unsafe fn force_unlock(&self) {
+        force_unlock();
+    }
+}
+
 #[inline(always)]
 pub fn mu_is_setup() -> bool {
     MiniUART::is_setup()
 }
 
-#[inline(always)]
+/// Enables the Mini UART interrupts and switches to the interrupt driven buffers. Must be called
+/// once, after the Mini UART is setup and [`irq::init`].
+pub fn init_irq() -> Result<(), IrqError> {
+    irq::register(IrqSource::AUX, handle_irq)?;
+    let mini_uart = MiniUART::acquire();
+    assert!(mini_uart.guard.is_some(), "Mini UART is not setup");
+    IRQ_MODE.store(true, Ordering::Release);
+    regs().ier.write(IER_RX);
+    irq::enable(IrqSource::AUX);
+    drop(mini_uart);
+    Ok(())
+}
+
+/// Receives a byte if one is available, without blocking.
+pub fn mu_try_recv() -> Option<u8> {
+    let _guard = RX_LOCK.lock();
+    if IRQ_MODE.load(Ordering::Acquire) {
+        // SAFETY: Consumers are serialized by `RX_LOCK`.
+        unsafe { RX_BUFFER.pop() }
+    } else {
+        let regs = regs();
+        (regs.lsr.read() & LSR_DATA_READY != 0).then(|| (regs.io.read() & 0xff) as u8)
+    }
+}
+
+/// Reads into `buf` the bytes that are available, sleeping until there is at least one. Returns
+/// the number of bytes read, which is only zero if `buf` is empty.
+pub fn mu_read(buf: &mut [u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    let mut read = 0;
+    while read == 0 {
+        while read < buf.len() {
+            match mu_try_recv() {
+                Some(byte) => buf[read] = byte,
+                None => break,
+            }
+            read += 1;
+        }
+        if read == 0 {
+            if IRQ_MODE.load(Ordering::Acquire) {
+                // The interrupt handler signals an event when it receives something.
+                cortex_a::asm::wfe();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+    read
+}
+
+/// Blocks until a byte is received through the UART, sleeping if the interrupts are enabled.
 pub fn mu_recv() -> u8 {
-    MiniUART::acquire().recv()
+    let mut byte = 0;
+    mu_read(core::slice::from_mut(&mut byte));
+    byte
+}
+
+/// Gets the number of received bytes that were dropped because nobody read them in time.
+pub fn mu_rx_overflows() -> usize {
+    RX_OVERFLOWS.load(Ordering::Relaxed)
+}
+
+/// Blocks until every byte sent was written to the UART.
+pub fn mu_flush() {
+    MiniUART::acquire().flush()
+}
+
+/// Releases [`LOCK`], [`RX_LOCK`] and [`TX_DRAINING`], see [`Console::force_unlock`].
+///
+/// # Safety
+///
+/// No other core may be using the Mini UART.
+pub unsafe fn force_unlock() {
+    if LOCK.is_locked() {
+        LOCK.force_unlock();
+    }
+    if RX_LOCK.is_locked() {
+        RX_LOCK.force_unlock();
+    }
+    TX_DRAINING.store(false, Ordering::Release);
+}
+
+/// Writes bytes from [`TX_BUFFER`] while the UART has space for them, unless someone else is
+/// already doing it.
+fn drain_tx() {
+    if TX_DRAINING
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+    let regs = regs();
+    while regs.lsr.read() & LSR_TX_EMPTY != 0 {
+        // SAFETY: The consumer is serialized by `TX_DRAINING`.
+        match unsafe { TX_BUFFER.pop() } {
+            Some(byte) => regs.io.write(byte as u32),
+            None => {
+                // Senders enable the interrupt after pushing, so it is only disabled while the
+                // buffer is seen empty afterwards.
+                regs.ier.write(IER_RX);
+                atomic::fence(Ordering::SeqCst);
+                if !TX_BUFFER.is_empty() {
+                    regs.ier.write(IER_RX | IER_TX);
+                }
+                break;
+            }
+        }
+    }
+    TX_DRAINING.store(false, Ordering::Release);
+}
+
+fn handle_irq() {
+    let regs = regs();
+    if regs.irq_status.read() & AUX_IRQ_MINI_UART == 0 {
+        return;
+    }
+
+    let mut received = false;
+    loop {
+        let lsr = regs.lsr.read();
+        if lsr & LSR_RX_OVERRUN != 0 {
+            RX_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+        }
+        if lsr & LSR_DATA_READY == 0 {
+            break;
+        }
+        let byte = (regs.io.read() & 0xff) as u8;
+        // SAFETY: The interrupt handler is the only producer.
+        if unsafe { RX_BUFFER.push(byte) }.is_err() {
+            RX_OVERFLOWS.fetch_add(1, Ordering::Relaxed);
+        }
+        received = true;
+    }
+    if received {
+        // Wake up the readers waiting in `mu_read`, possibly on other cores.
+        cortex_a::asm::sev();
+    }
+
+    drain_tx();
 }
 
 #[inline(always)]
@@ -0,0 +1,479 @@
+//! The PL011 UART.
+//!
+//! Unlike the Mini UART, its baud rate is derived from a dedicated UART clock, which doesn't change
+//! with the VPU clock. Sending spins on its 16 byte transmit FIFO while holding [`LOCK`]. Receiving
+//! is polled until [`init_irq`] is called, after which the receive interrupts move the FIFO into
+//! [`RX_BUFFER`]. Receive errors are reported once, as a [`Pl011Error`], by the next read.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use super::{
+    gpio::{Alt, Pin, Pull},
+    Reg32, MMIO_BASE_ADDR,
+};
+use crate::console::Console;
+use crate::error::{Error, ErrorKind};
+use crate::irq::{self, IrqError, IrqSource};
+use crate::utils::ring_buffer::RingBuffer;
+
+/// Frequency of the UART clock set by the firmware.
+pub const UART_CLOCK_HZ: u32 = 48_000_000;
+
+const DR_FRAMING_ERROR: u32 = 1 << 8;
+const DR_PARITY_ERROR: u32 = 1 << 9;
+const DR_BREAK_ERROR: u32 = 1 << 10;
+const DR_OVERRUN_ERROR: u32 = 1 << 11;
+const DR_ERRORS: u32 = DR_FRAMING_ERROR | DR_PARITY_ERROR | DR_BREAK_ERROR | DR_OVERRUN_ERROR;
+
+const FR_BUSY: u32 = 1 << 3;
+const FR_RX_EMPTY: u32 = 1 << 4;
+const FR_TX_FULL: u32 = 1 << 5;
+
+const LCRH_PARITY_ENABLE: u32 = 1 << 1;
+const LCRH_EVEN_PARITY: u32 = 1 << 2;
+const LCRH_TWO_STOP_BITS: u32 = 1 << 3;
+const LCRH_FIFO_ENABLE: u32 = 1 << 4;
+
+const CR_UART_ENABLE: u32 = 1 << 0;
+const CR_TX_ENABLE: u32 = 1 << 8;
+const CR_RX_ENABLE: u32 = 1 << 9;
+
+const INT_RX: u32 = 1 << 4;
+const INT_RX_TIMEOUT: u32 = 1 << 6;
+const INT_ALL: u32 = 0x7ff;
+
+const RX_BUFFER_SIZE: usize = 1024;
+
+#[repr(C)]
+struct Pl011Registers {
+    data: Reg32,
+    rx_status: Reg32,
+    _reserved0: [Reg32; 4],
+    flags: Reg32,
+    _reserved1: Reg32,
+    ilpr: Reg32,
+    integer_baud: Reg32,
+    fractional_baud: Reg32,
+    line_control: Reg32,
+    control: Reg32,
+    fifo_level_select: Reg32,
+    irq_mask: Reg32,
+    raw_irq_status: Reg32,
+    masked_irq_status: Reg32,
+    irq_clear: Reg32,
+}
+
+impl Pl011Registers {
+    const REGS_ADDR: usize = MMIO_BASE_ADDR + 0x201000;
+
+    #[inline(always)]
+    pub const fn get() -> *mut Self {
+        Self::REGS_ADDR as *mut Self
+    }
+}
+
+/// Errors reported by the PL011.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pl011Error {
+    /// A character didn't have a valid stop bit.
+    Framing,
+    /// A character had the wrong parity.
+    Parity,
+    /// The receive line was held low for longer than a character.
+    Break,
+    /// A character was received while the receive FIFO or buffer was full, and was lost.
+    Overrun,
+    /// The baud rate can't be generated from the UART clock.
+    InvalidBaudRate,
+    /// The number of data bits is not between 5 and 8.
+    InvalidDataBits,
+}
+
+impl Pl011Error {
+    /// Converts the error bits of the data register, or of [`PENDING_ERRORS`], to the most severe
+    /// error among them.
+    fn from_bits(bits: u32) -> Option<Self> {
+        if bits & DR_OVERRUN_ERROR != 0 {
+            Some(Pl011Error::Overrun)
+        } else if bits & DR_BREAK_ERROR != 0 {
+            Some(Pl011Error::Break)
+        } else if bits & DR_FRAMING_ERROR != 0 {
+            Some(Pl011Error::Framing)
+        } else if bits & DR_PARITY_ERROR != 0 {
+            Some(Pl011Error::Parity)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Pl011Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Pl011Error::Framing => "framing error",
+            Pl011Error::Parity => "parity error",
+            Pl011Error::Break => "break condition",
+            Pl011Error::Overrun => "receive overrun",
+            Pl011Error::InvalidBaudRate => "invalid baud rate",
+            Pl011Error::InvalidDataBits => "invalid data bits",
+        })
+    }
+}
+
+impl Error for Pl011Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Pl011Error::InvalidBaudRate | Pl011Error::InvalidDataBits => ErrorKind::InvalidArgument,
+            _ => ErrorKind::DeviceError,
+        }
+    }
+}
+
+/// Parity bit of each character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    /// No parity bit.
+    None,
+    /// Even parity.
+    Even,
+    /// Odd parity.
+    Odd,
+}
+
+/// Number of stop bits of each character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    /// One stop bit.
+    One,
+    /// Two stop bits.
+    Two,
+}
+
+/// How full a FIFO is when its interrupt fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum FifoLevel {
+    /// 1/8 full.
+    OneEighth = 0b000,
+    /// 1/4 full.
+    OneQuarter = 0b001,
+    /// 1/2 full.
+    Half = 0b010,
+    /// 3/4 full.
+    ThreeQuarters = 0b011,
+    /// 7/8 full.
+    SevenEighths = 0b100,
+}
+
+/// Line and FIFO configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// Baud rate, in bits per second.
+    pub baud_rate: u32,
+    /// Bits per character, from 5 to 8.
+    pub data_bits: u8,
+    /// Parity bit.
+    pub parity: Parity,
+    /// Stop bits.
+    pub stop_bits: StopBits,
+    /// Level of the receive FIFO at which the receive interrupt fires.
+    pub rx_fifo_level: FifoLevel,
+    /// Level of the transmit FIFO at which the transmit interrupt fires.
+    pub tx_fifo_level: FifoLevel,
+}
+
+impl Default for Config {
+    /// 115200 baud, 8 data bits, no parity and one stop bit.
+    fn default() -> Self {
+        Config {
+            baud_rate: 115200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            rx_fifo_level: FifoLevel::Half,
+            tx_fifo_level: FifoLevel::Half,
+        }
+    }
+}
+
+/// Bytes received by the interrupt handler and not read yet.
+static RX_BUFFER: RingBuffer<RX_BUFFER_SIZE> = RingBuffer::new();
+/// Serializes the consumers of [`RX_BUFFER`], as well as polling reads.
+static RX_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+/// Whether [`RX_BUFFER`] is used, set by [`init_irq`].
+static IRQ_MODE: AtomicBool = AtomicBool::new(false);
+/// Error bits, as in the data register, that were not reported yet.
+static PENDING_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// The pin the PL011 sends on.
+pub type TxPin = Pin<14, Alt<0>>;
+/// The pin the PL011 receives on.
+pub type RxPin = Pin<15, Alt<0>>;
+
+/// Global PL011 lock. When the value inside the mutex is `None` it means that the PL011 was not
+/// setup.
+static LOCK: spin::Mutex<Option<&'static mut Pl011Registers>> = spin::Mutex::new(None);
+
+#[inline(always)]
+fn regs() -> &'static mut Pl011Registers {
+    unsafe { &mut *Pl011Registers::get() }
+}
+
+/// Structure that represents an exclusive handle to the PL011.
+pub struct Pl011 {
+    guard: spin::MutexGuard<'static, Option<&'static mut Pl011Registers>>,
+}
+
+impl Pl011 {
+    /// Acquires exclusively the PL011. Like [`super::MiniUART::acquire`], **it will deadlock** if
+    /// the same thread is already holding it.
+    pub fn acquire() -> Self {
+        Pl011 { guard: LOCK.lock() }
+    }
+
+    /// Checks whether the PL011 is setup.
+    pub fn is_setup() -> bool {
+        Pl011::acquire().guard.is_some()
+    }
+
+    /// Initializes the PL011 with [`Config::default`].
+    pub fn init_default(&mut self, tx: TxPin, rx: RxPin) {
+        self.init(tx, rx, Config::default())
+            .expect("the default configuration is valid");
+    }
+
+    /// Initializes the PL011 on GPIO pins 14 and 15, which are shared with the Mini UART. The pins
+    /// are kept by the PL011, and stay claimed, even if the configuration is invalid.
+    pub fn init(&mut self, mut tx: TxPin, mut rx: RxPin, config: Config) -> Result<(), Pl011Error> {
+        // The divisor is UART_CLOCK_HZ / (16 * baud_rate), with 6 fractional bits.
+        let baud = config.baud_rate as u64;
+        if baud == 0 {
+            return Err(Pl011Error::InvalidBaudRate);
+        }
+        let divisor = (UART_CLOCK_HZ as u64 * 4 + baud / 2) / baud;
+        let integer = divisor >> 6;
+        if !(1..=0xffff).contains(&integer) {
+            return Err(Pl011Error::InvalidBaudRate);
+        }
+        if !(5..=8).contains(&config.data_bits) {
+            return Err(Pl011Error::InvalidDataBits);
+        }
+
+        let regs = regs();
+        regs.control.write(0);
+        while regs.flags.read() & FR_BUSY != 0 {
+            core::hint::spin_loop();
+        }
+        // Flushes the FIFOs.
+        regs.line_control.write(0);
+
+        tx.set_pull(Pull::None);
+        rx.set_pull(Pull::None);
+
+        regs.integer_baud.write(integer as u32);
+        regs.fractional_baud.write((divisor & 0x3f) as u32);
+        let mut line_control = LCRH_FIFO_ENABLE | (config.data_bits as u32 - 5) << 5;
+        match config.parity {
+            Parity::None => (),
+            Parity::Even => line_control |= LCRH_PARITY_ENABLE | LCRH_EVEN_PARITY,
+            Parity::Odd => line_control |= LCRH_PARITY_ENABLE,
+        }
+        if config.stop_bits == StopBits::Two {
+            line_control |= LCRH_TWO_STOP_BITS;
+        }
+        // Must be written after the baud rate registers, to latch them.
+        regs.line_control.write(line_control);
+        regs.fifo_level_select
+            .write((config.rx_fifo_level as u32) << 3 | config.tx_fifo_level as u32);
+        regs.irq_mask.write(0);
+        regs.irq_clear.write(INT_ALL);
+        regs.control
+            .write(CR_UART_ENABLE | CR_TX_ENABLE | CR_RX_ENABLE);
+
+        self.guard.replace(regs);
+        Ok(())
+    }
+
+    /// Sends a single byte. Spins while the transmit FIFO is full.
+    pub fn send(&mut self, byte: u8) {
+        let regs = self
+            .guard
+            .as_mut()
+            .expect("PL011 is not setup while trying to send data");
+
+        while regs.flags.read() & FR_TX_FULL != 0 {
+            core::hint::spin_loop();
+        }
+        regs.data.write(byte as u32);
+    }
+
+    /// Writes a buffer of bytes to the UART.
+    pub fn write(&mut self, buf: &[u8]) {
+        for &byte in buf {
+            self.send(byte);
+        }
+    }
+
+    /// Blocks until the transmit FIFO is empty and the last byte was sent.
+    pub fn flush(&mut self) {
+        if let Some(regs) = self.guard.as_mut() {
+            while regs.flags.read() & FR_BUSY != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl fmt::Write for Pl011 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Enables the receive interrupts and switches to the interrupt driven receive buffer. Must be
+/// called once, after the PL011 is setup and [`irq::init`].
+pub fn init_irq() -> Result<(), IrqError> {
+    irq::register(IrqSource::PL011, handle_irq)?;
+    let pl011 = Pl011::acquire();
+    assert!(pl011.guard.is_some(), "PL011 is not setup");
+    IRQ_MODE.store(true, Ordering::Release);
+    regs().irq_mask.write(INT_RX | INT_RX_TIMEOUT);
+    irq::enable(IrqSource::PL011);
+    drop(pl011);
+    Ok(())
+}
+
+/// Reports the errors received since the last call, if any.
+fn take_error() -> Result<(), Pl011Error> {
+    match Pl011Error::from_bits(PENDING_ERRORS.swap(0, Ordering::AcqRel)) {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Receives a byte if one is available, without blocking.
+pub fn try_recv() -> Result<Option<u8>, Pl011Error> {
+    let _guard = RX_LOCK.lock();
+    take_error()?;
+    if IRQ_MODE.load(Ordering::Acquire) {
+        // SAFETY: Consumers are serialized by `RX_LOCK`.
+        return Ok(unsafe { RX_BUFFER.pop() });
+    }
+    let regs = regs();
+    if regs.flags.read() & FR_RX_EMPTY != 0 {
+        return Ok(None);
+    }
+    let data = regs.data.read();
+    match Pl011Error::from_bits(data & DR_ERRORS) {
+        Some(err) => Err(err),
+        None => Ok(Some(data as u8)),
+    }
+}
+
+/// Reads into `buf` the bytes that are available, sleeping until there is at least one. Returns
+/// the number of bytes read, which is only zero if `buf` is empty.
+pub fn read(buf: &mut [u8]) -> Result<usize, Pl011Error> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    let mut read = 0;
+    while read == 0 {
+        while read < buf.len() {
+            match try_recv()? {
+                Some(byte) => buf[read] = byte,
+                None => break,
+            }
+            read += 1;
+        }
+        if read == 0 {
+            if IRQ_MODE.load(Ordering::Acquire) {
+                // The interrupt handler signals an event when it receives something.
+                cortex_a::asm::wfe();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+    Ok(read)
+}
+
+/// Releases [`LOCK`] and [`RX_LOCK`], see [`Console::force_unlock`].
+///
+/// # Safety
+///
+/// No other core may be using the PL011.
+pub unsafe fn force_unlock() {
+    if LOCK.is_locked() {
+        LOCK.force_unlock();
+    }
+    if RX_LOCK.is_locked() {
+        RX_LOCK.force_unlock();
+    }
+}
+
+fn handle_irq() {
+    let regs = regs();
+    regs.irq_clear.write(INT_RX | INT_RX_TIMEOUT);
+
+    let mut received = false;
+    while regs.flags.read() & FR_RX_EMPTY == 0 {
+        let data = regs.data.read();
+        received = true;
+        if data & DR_ERRORS != 0 {
+            PENDING_ERRORS.fetch_or(data & DR_ERRORS, Ordering::AcqRel);
+            continue;
+        }
+        // SAFETY: The interrupt handler is the only producer.
+        if unsafe { RX_BUFFER.push(data as u8) }.is_err() {
+            PENDING_ERRORS.fetch_or(DR_OVERRUN_ERROR, Ordering::AcqRel);
+        }
+    }
+    if received {
+        // Wake up the readers waiting in `read`, possibly on other cores.
+        cortex_a::asm::sev();
+    }
+}
+
+/// The PL011 as a [`Console`]. Receive errors are ignored, since the bytes involved are already
+/// dropped.
+pub struct Pl011Console;
+
+/// The PL011 console, to be registered once the PL011 is setup.
+pub static PL011_CONSOLE: Pl011Console = Pl011Console;
+
+impl Console for Pl011Console {
+    fn name(&self) -> &'static str {
+        "pl011"
+    }
+
+    fn write_bytes(&self, buf: &[u8]) {
+        Pl011::acquire().write(buf);
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        try_recv().ok().flatten()
+    }
+
+    fn flush(&self) {
+        Pl011::acquire().flush();
+    }
+
+    unsafe fn force_unlock(&self) {
+        force_unlock();
+    }
+}
+
+#[doc(hidden)]
+pub fn _pl011_print(args: fmt::Arguments) {
+    use fmt::Write;
+
+    if !Pl011::is_setup() {
+        panic!("PL011 is expected to be initialized before printing to it");
+    }
+    // SAFETY: Writing to the PL011 never fails.
+    unsafe { Pl011::acquire().write_fmt(args).unwrap_unchecked() };
+}
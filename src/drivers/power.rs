@@ -0,0 +1,105 @@
+//! Power management block of the BCM2835, used to reset or halt the board through its watchdog.
+//!
+//! Writes to the registers are ignored unless they carry [`PM_PASSWORD`] in their top byte. Once
+//! the watchdog is armed, it counts down at 65536 Hz and resets the board with the configuration of
+//! the reset control register when it reaches zero. See [`watchdog`] to use it as a watchdog.
+//!
+//! None of the functions take a lock, so they can be used from the panic handler. Each register is
+//! only written with a single store, which at worst makes concurrent calls override each other.
+
+pub mod watchdog;
+
+use super::{Reg32, MMIO_BASE_ADDR};
+use crate::time::Duration;
+
+const PM_PASSWORD: u32 = 0x5a00_0000;
+const PM_RSTC_WRCFG_MASK: u32 = 0x30;
+const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x20;
+/// Partition the firmware boots from after a reset, spread over the even bits of `PM_RSTS`.
+const PM_RSTS_PARTITION_MASK: u32 = 0x555;
+/// Partition 63, which the firmware of the Raspberry Pi treats as a request to halt.
+const PM_RSTS_HALT: u32 = 0x555;
+const PM_WDOG_TIME_MASK: u32 = 0x000f_ffff;
+const WDOG_TICKS_PER_SEC: u64 = 1 << 16;
+/// Watchdog ticks before the reset used by [`reboot`] and [`halt`].
+const RESET_TICKS: u32 = 10;
+
+#[repr(C)]
+struct PmRegisters {
+    _reserved0: [Reg32; 7],
+    rstc: Reg32,
+    rsts: Reg32,
+    wdog: Reg32,
+}
+
+impl PmRegisters {
+    const REGS_ADDR: usize = MMIO_BASE_ADDR + 0x10_0000;
+
+    /// # Safety
+    ///
+    /// Every reference returned aliases the same registers.
+    #[inline(always)]
+    unsafe fn get() -> &'static mut Self {
+        &mut *(Self::REGS_ADDR as *mut Self)
+    }
+}
+
+/// Converts `timeout` to watchdog ticks, clamped to what the watchdog can count.
+fn wdog_ticks(timeout: Duration) -> u32 {
+    let ticks = timeout.as_micros() * WDOG_TICKS_PER_SEC as u128 / 1_000_000;
+    ticks.clamp(1, PM_WDOG_TIME_MASK as u128) as u32
+}
+
+/// Converts watchdog ticks to a duration.
+fn wdog_duration(ticks: u32) -> Duration {
+    Duration::from_micros(ticks as u64 * 1_000_000 / WDOG_TICKS_PER_SEC)
+}
+
+/// Longest delay the watchdog can count, about 16 seconds.
+pub fn max_timeout() -> Duration {
+    wdog_duration(PM_WDOG_TIME_MASK)
+}
+
+/// Sets the watchdog to reset the board after `ticks`.
+fn arm(ticks: u32) {
+    // SAFETY: The registers are only written with single stores.
+    let regs = unsafe { PmRegisters::get() };
+    let rstc = regs.rstc.read() & !PM_RSTC_WRCFG_MASK;
+    regs.wdog.write(PM_PASSWORD | (ticks & PM_WDOG_TIME_MASK));
+    regs.rstc
+        .write(PM_PASSWORD | rstc | PM_RSTC_WRCFG_FULL_RESET);
+}
+
+/// Arms the watchdog to reset the board once `delay` elapsed, up to [`max_timeout`].
+pub fn reset_after(delay: Duration) {
+    arm(wdog_ticks(delay));
+}
+
+/// Sets the partition booted by the firmware after the next reset.
+fn set_boot_partition(partition: u32) {
+    // SAFETY: The registers are only written with single stores.
+    let regs = unsafe { PmRegisters::get() };
+    let rsts = regs.rsts.read() & !PM_RSTS_PARTITION_MASK;
+    regs.rsts.write(PM_PASSWORD | rsts | partition);
+}
+
+fn wait_for_reset() -> ! {
+    loop {
+        cortex_a::asm::wfe();
+    }
+}
+
+/// Resets the board.
+pub fn reboot() -> ! {
+    set_boot_partition(0);
+    arm(RESET_TICKS);
+    wait_for_reset()
+}
+
+/// Halts the board. It is actually reset, but the firmware stops instead of booting the kernel
+/// again, until the power is cycled. QEMU doesn't know about this, and just resets.
+pub fn halt() -> ! {
+    set_boot_partition(PM_RSTS_HALT);
+    arm(RESET_TICKS);
+    wait_for_reset()
+}
@@ -0,0 +1,52 @@
+//! The watchdog of the power management block, which resets the board unless it is petted before
+//! its timeout elapses.
+//!
+//! This is the same countdown used by [`super::reboot`] and [`super::reset_after`], so calling
+//! them replaces the timeout of the watchdog.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use super::{
+    arm, wdog_duration, wdog_ticks, PmRegisters, PM_PASSWORD, PM_RSTC_WRCFG_MASK, PM_WDOG_TIME_MASK,
+};
+use crate::time::Duration;
+
+/// Timeout of the running watchdog in ticks, zero when it is stopped.
+static TIMEOUT_TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// Starts the watchdog, which resets the board unless [`pet`] is called within `timeout`. The
+/// timeout is clamped to [`super::max_timeout`].
+pub fn start(timeout: Duration) {
+    let ticks = wdog_ticks(timeout);
+    TIMEOUT_TICKS.store(ticks, Ordering::Relaxed);
+    arm(ticks);
+}
+
+/// Restarts the countdown of the watchdog, if it is running.
+pub fn pet() {
+    match TIMEOUT_TICKS.load(Ordering::Relaxed) {
+        0 => (),
+        ticks => arm(ticks),
+    }
+}
+
+/// Stops the watchdog.
+pub fn stop() {
+    TIMEOUT_TICKS.store(0, Ordering::Relaxed);
+    // SAFETY: The registers are only written with single stores.
+    let regs = unsafe { PmRegisters::get() };
+    let rstc = regs.rstc.read() & !PM_RSTC_WRCFG_MASK;
+    regs.rstc.write(PM_PASSWORD | rstc);
+}
+
+/// Whether the watchdog was started and not stopped.
+pub fn is_running() -> bool {
+    TIMEOUT_TICKS.load(Ordering::Relaxed) != 0
+}
+
+/// Time left before the watchdog resets the board.
+pub fn time_left() -> Duration {
+    // SAFETY: Only reads the counter.
+    let regs = unsafe { PmRegisters::get() };
+    wdog_duration(regs.wdog.read() & PM_WDOG_TIME_MASK)
+}
@@ -0,0 +1,173 @@
+//! BCM2835 system timer, a free running 64 bit counter at 1 MHz with four compare channels.
+//!
+//! Channels 0 and 2 are used by the GPU firmware, so only [`Channel::One`] and [`Channel::Three`]
+//! are available. A channel raises its interrupt when the low 32 bits of the counter match its
+//! compare register, and stays pending until its bit in the control/status register is cleared.
+
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use super::{Reg32, MMIO_BASE_ADDR};
+use crate::irq::{self, IrqError, IrqSource};
+
+/// A callback called from the interrupt handler when a compare channel matches.
+pub type Callback = fn();
+
+#[repr(C)]
+struct SystemTimerRegisters {
+    control_status: Reg32,
+    counter_low: Reg32,
+    counter_high: Reg32,
+    compare: [Reg32; 4],
+}
+
+impl SystemTimerRegisters {
+    const REGS_ADDR: usize = MMIO_BASE_ADDR + 0x3000;
+
+    /// # Safety
+    ///
+    /// Every reference returned aliases the same registers. The compare registers must only be
+    /// written while holding [`LOCK`].
+    #[inline(always)]
+    unsafe fn get() -> &'static mut Self {
+        &mut *(Self::REGS_ADDR as *mut Self)
+    }
+}
+
+/// The compare channels available to the ARM cores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Channel {
+    /// Compare channel 1.
+    One = 1,
+    /// Compare channel 3.
+    Three = 3,
+}
+
+impl Channel {
+    fn irq(self) -> IrqSource {
+        match self {
+            Channel::One => IrqSource::SYSTEM_TIMER_1,
+            Channel::Three => IrqSource::SYSTEM_TIMER_3,
+        }
+    }
+
+    fn state(self) -> &'static ChannelState {
+        &CHANNELS[(self as usize) / 2]
+    }
+}
+
+/// Callback of a channel, zero when unused, and its period in microseconds, zero for one-shot.
+struct ChannelState {
+    callback: AtomicUsize,
+    period: AtomicU32,
+}
+
+static CHANNELS: [ChannelState; 2] = [const {
+    ChannelState {
+        callback: AtomicUsize::new(0),
+        period: AtomicU32::new(0),
+    }
+}; 2];
+
+/// Serializes writes to the compare registers. Always taken with interrupts masked, since the
+/// interrupt handler also takes it.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Reads the 64 bit counter, in microseconds.
+pub fn counter() -> u64 {
+    let regs = unsafe { SystemTimerRegisters::get() };
+    // The high word is read twice in case the low word overflowed in between.
+    loop {
+        let high = regs.counter_high.read();
+        let low = regs.counter_low.read();
+        if regs.counter_high.read() == high {
+            return (high as u64) << 32 | low as u64;
+        }
+    }
+}
+
+/// Busy waits for `us` microseconds.
+pub fn delay_us(us: u64) {
+    let start = counter();
+    while counter().wrapping_sub(start) < us {
+        core::hint::spin_loop();
+    }
+}
+
+/// Registers the interrupt handlers of the compare channels. Must be called once, after
+/// [`irq::init`].
+pub fn init() -> Result<(), IrqError> {
+    irq::register(Channel::One.irq(), handle_channel_1)?;
+    irq::register(Channel::Three.irq(), handle_channel_3)?;
+    irq::enable(Channel::One.irq());
+    irq::enable(Channel::Three.irq());
+    Ok(())
+}
+
+fn arm(channel: Channel, delay_us: u32, period_us: u32, callback: Callback) {
+    irq::without_interrupts(|| {
+        let _guard = LOCK.lock();
+        let regs = unsafe { SystemTimerRegisters::get() };
+        let state = channel.state();
+        state.period.store(period_us, Ordering::Relaxed);
+        state.callback.store(callback as usize, Ordering::Relaxed);
+        regs.control_status.write(1 << channel as u32);
+        let now = regs.counter_low.read();
+        regs.compare[channel as usize].write(now.wrapping_add(delay_us.max(1)));
+    })
+}
+
+/// Calls `callback` once after `delay_us` microseconds, replacing anything scheduled on `channel`.
+pub fn schedule(channel: Channel, delay_us: u32, callback: Callback) {
+    arm(channel, delay_us, 0, callback)
+}
+
+/// Calls `callback` every `period_us` microseconds, replacing anything scheduled on `channel`.
+pub fn schedule_periodic(channel: Channel, period_us: u32, callback: Callback) {
+    arm(channel, period_us, period_us.max(1), callback)
+}
+
+/// Cancels the callback scheduled on `channel`, and clears its pending match, if any. A periodic
+/// callback isn't re-armed anymore.
+pub fn cancel(channel: Channel) {
+    irq::without_interrupts(|| {
+        let _guard = LOCK.lock();
+        let regs = unsafe { SystemTimerRegisters::get() };
+        let state = channel.state();
+        state.period.store(0, Ordering::Relaxed);
+        state.callback.store(0, Ordering::Relaxed);
+        regs.control_status.write(1 << channel as u32);
+    })
+}
+
+fn handle_channel_1() {
+    handle(Channel::One)
+}
+
+fn handle_channel_3() {
+    handle(Channel::Three)
+}
+
+fn handle(channel: Channel) {
+    let callback = {
+        let _guard = LOCK.lock();
+        let regs = unsafe { SystemTimerRegisters::get() };
+        let state = channel.state();
+        regs.control_status.write(1 << channel as u32);
+        let period = state.period.load(Ordering::Relaxed);
+        if period == 0 {
+            state.callback.swap(0, Ordering::Relaxed)
+        } else {
+            // Relative to the previous match, so that the period doesn't drift.
+            let compare = regs.compare[channel as usize].read();
+            regs.compare[channel as usize].write(compare.wrapping_add(period));
+            state.callback.load(Ordering::Relaxed)
+        }
+    };
+    if callback != 0 {
+        // SAFETY: Only `Callback`s are stored in the channels.
+        unsafe { core::mem::transmute::<usize, Callback>(callback)() };
+    }
+}
@@ -1,16 +1,225 @@
-use core::fmt::{Debug, Display};
+//! Kernel errors.
+//!
+//! Errors of specific modules are plain types implementing [`Error`]. Code that can fail in many
+//! ways returns a [`KError`] instead, which any [`Error`] converts into with `?`, keeping its
+//! [`ErrorKind`]. Context can be attached to a [`KError`] with [`KError::context`] or
+//! [`Context::context`], which wraps the original error as its source.
+//!
+//! A [`KError`] lives on the heap, so creating one can fail. When it does, the error is replaced by
+//! [`KError::out_of_memory`], which never allocates.
+
+use core::fmt::{self, Debug, Display};
 
 use crate::allocators::KBox;
 
-pub trait Error: Debug + Display {}
+/// The trait implemented by all the error types of the kernel.
+pub trait Error: Debug + Display {
+    /// Category of the error.
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
 
-pub type KError = KBox<'static, dyn Error + 'static>;
+    /// The error that caused this one, if any.
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
 
 impl Error for &'static str {}
 
-impl From<&'static str> for KBox<'static, dyn Error> {
-    fn from(val: &'static str) -> Self {
-        // FIXME: This is not Ok. It is a safe interface to an unsafe function.
-        unsafe { KBox::new(val) }
+/// Category of an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Memory or some other fixed pool of resources was exhausted.
+    OutOfMemory,
+    /// An argument was not valid.
+    InvalidArgument,
+    /// An operation didn't complete in time.
+    Timeout,
+    /// A device reported an error.
+    DeviceError,
+    /// Something that was looked for doesn't exist.
+    NotFound,
+    /// Something that was created already exists.
+    AlreadyExists,
+    /// The operation is not supported.
+    Unsupported,
+    /// Any other error.
+    Other,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::OutOfMemory => "out of memory",
+            ErrorKind::InvalidArgument => "invalid argument",
+            ErrorKind::Timeout => "timed out",
+            ErrorKind::DeviceError => "device error",
+            ErrorKind::NotFound => "not found",
+            ErrorKind::AlreadyExists => "already exists",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::Other => "error",
+        })
+    }
+}
+
+/// A kernel error, with a kind, a message and optionally the error that caused it.
+///
+/// Formatting it with `{}` only shows its message, while `{:#}` also shows the messages of its
+/// sources, separated by colons.
+pub struct KError(Repr);
+
+enum Repr {
+    /// Doesn't allocate, so that running out of memory can always be reported.
+    OutOfMemory,
+    Boxed(KBox<ErrorImpl>),
+}
+
+struct ErrorImpl {
+    kind: ErrorKind,
+    message: Message,
+    source: Option<KError>,
+}
+
+enum Message {
+    Static(&'static str),
+    Error(KBox<dyn Error>),
+}
+
+impl KError {
+    /// The out of memory error, which never allocates.
+    pub const fn out_of_memory() -> Self {
+        KError(Repr::OutOfMemory)
+    }
+
+    /// Creates an error of `kind` with `message`.
+    pub fn new(kind: ErrorKind, message: &'static str) -> Self {
+        KError::from_impl(ErrorImpl {
+            kind,
+            message: Message::Static(message),
+            source: None,
+        })
+    }
+
+    /// Wraps `err`, keeping its kind.
+    pub fn from_error<E: Error + 'static>(err: E) -> Self {
+        let kind = err.kind();
+        match KBox::try_new(err) {
+            Ok(err) => KError::from_impl(ErrorImpl {
+                kind,
+                message: Message::Error(err),
+                source: None,
+            }),
+            Err(_) => KError::out_of_memory(),
+        }
+    }
+
+    fn from_impl(imp: ErrorImpl) -> Self {
+        match KBox::try_new(imp) {
+            Ok(imp) => KError(Repr::Boxed(imp)),
+            Err(_) => KError::out_of_memory(),
+        }
+    }
+
+    /// Category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match &self.0 {
+            Repr::OutOfMemory => ErrorKind::OutOfMemory,
+            Repr::Boxed(imp) => imp.kind,
+        }
+    }
+
+    /// Wraps the error in a new one of the same kind, with `message` explaining what was being
+    /// done.
+    pub fn context(self, message: &'static str) -> Self {
+        let kind = self.kind();
+        match self.0 {
+            // Keeps reporting out of memory without allocating.
+            Repr::OutOfMemory => self,
+            Repr::Boxed(_) => KError::from_impl(ErrorImpl {
+                kind,
+                message: Message::Static(message),
+                source: Some(self),
+            }),
+        }
+    }
+
+    /// Changes the kind of the error.
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        if let Repr::Boxed(imp) = &mut self.0 {
+            imp.kind = kind;
+        }
+        self
+    }
+
+    /// The error this one wraps, if it was created with [`KError::context`].
+    pub fn source(&self) -> Option<&KError> {
+        match &self.0 {
+            Repr::OutOfMemory => None,
+            Repr::Boxed(imp) => imp.source.as_ref(),
+        }
+    }
+
+    /// Iterates over this error and its sources.
+    pub fn chain(&self) -> impl Iterator<Item = &KError> {
+        core::iter::successors(Some(self), |err| err.source())
+    }
+
+    /// Writes the message of this error only, including the sources of a wrapped [`Error`] if
+    /// `alternate` is set.
+    fn fmt_message(&self, f: &mut fmt::Formatter, alternate: bool) -> fmt::Result {
+        match &self.0 {
+            Repr::OutOfMemory => f.write_str("out of memory"),
+            Repr::Boxed(imp) => match &imp.message {
+                Message::Static(msg) => f.write_str(msg),
+                Message::Error(err) => {
+                    Display::fmt(err, f)?;
+                    let mut source = err.source();
+                    while let (true, Some(err)) = (alternate, source) {
+                        write!(f, ": {}", err)?;
+                        source = err.source();
+                    }
+                    Ok(())
+                }
+            },
+        }
+    }
+}
+
+impl Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let alternate = f.alternate();
+        self.fmt_message(f, alternate)?;
+        if alternate {
+            for err in self.chain().skip(1) {
+                f.write_str(": ")?;
+                err.fmt_message(f, true)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for KError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {:#}", self.kind(), self)
+    }
+}
+
+impl<E: Error + 'static> From<E> for KError {
+    fn from(err: E) -> Self {
+        KError::from_error(err)
+    }
+}
+
+/// Adds context to the errors of a `Result`.
+pub trait Context<T> {
+    /// Converts the error to a [`KError`] and wraps it with `message`, see [`KError::context`].
+    fn context(self, message: &'static str) -> Result<T, KError>;
+}
+
+impl<T, E: Into<KError>> Context<T> for Result<T, E> {
+    fn context(self, message: &'static str) -> Result<T, KError> {
+        self.map_err(|err| err.into().context(message))
     }
 }
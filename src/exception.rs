@@ -0,0 +1,477 @@
+//! Exception vectors and handlers.
+//!
+//! The vector table lives in `exception/vectors.S`. Every entry saves the register state into an
+//! [`ExceptionFrame`] and calls [`_exception_handler`]. IRQs are dispatched to the handlers
+//! registered in [`crate::irq`]. For everything else the handler decodes the syndrome register and,
+//! since nothing is able to recover from those yet, prints a crash report and panics.
+
+use core::fmt;
+
+use cortex_a::asm::barrier;
+use tock_registers::interfaces::Writeable;
+
+use crate::utils::{get_cpu, get_current_exception_level};
+
+core::arch::global_asm!(include_str!("exception/vectors.S"));
+
+extern "C" {
+    #[link_name = "__exception_vectors_EL1"]
+    static EXCEPTION_VECTORS_EL1: u8;
+}
+
+/// Installs the exception vector table for the current core in `VBAR_EL1`. Must be called on every
+/// core. The EL2 vector table is installed by `boot.S` before dropping to EL1.
+pub fn init() {
+    use cortex_a::registers::VBAR_EL1;
+
+    // SAFETY: The symbol is defined in `vectors.S`, only its address is used.
+    unsafe {
+        VBAR_EL1.set(&EXCEPTION_VECTORS_EL1 as *const u8 as u64);
+        barrier::isb(barrier::SY);
+    }
+}
+
+/// The register state saved by the vector table when an exception is taken. The layout must match
+/// the one used in `vectors.S`.
+#[repr(C)]
+pub struct ExceptionFrame {
+    /// General purpose registers `x0` to `x30`.
+    pub gpr: [u64; 31],
+    /// Exception link register, the address the exception returns to.
+    pub elr: u64,
+    /// Saved program status register.
+    pub spsr: u64,
+    /// Exception syndrome register.
+    pub esr: u64,
+    /// Fault address register.
+    pub far: u64,
+    _padding: u64,
+}
+
+impl ExceptionFrame {
+    /// Decodes the syndrome of the exception.
+    pub fn syndrome(&self) -> Syndrome {
+        Syndrome::decode(Esr(self.esr), self.far)
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "      ELR: {:#018x}  SPSR: {:#010x}", self.elr, self.spsr)?;
+        write!(f, "      ESR: {:#010x}   FAR: {:#018x}", self.esr, self.far)?;
+        for (i, reg) in self.gpr.iter().enumerate() {
+            if i % 3 == 0 {
+                writeln!(f)?;
+            }
+            write!(f, "      x{:<2}: {:#018x}", i, reg)?;
+        }
+        Ok(())
+    }
+}
+
+/// The kind of exception, which depends on the vector entry that was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionKind {
+    /// Synchronous exception, caused directly by an instruction.
+    Synchronous,
+    /// Interrupt request.
+    Irq,
+    /// Fast interrupt request.
+    Fiq,
+    /// System error, an asynchronous abort.
+    SError,
+}
+
+/// Where the exception was taken from, which depends on the vector entry that was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionOrigin {
+    /// Current exception level while using `SP_EL0`.
+    CurrentElSp0,
+    /// Current exception level while using `SP_ELx`.
+    CurrentElSpx,
+    /// Lower exception level running AArch64.
+    LowerElAarch64,
+    /// Lower exception level running AArch32.
+    LowerElAarch32,
+}
+
+impl ExceptionKind {
+    fn from_vector(vector: u64) -> Self {
+        match vector % 4 {
+            0 => ExceptionKind::Synchronous,
+            1 => ExceptionKind::Irq,
+            2 => ExceptionKind::Fiq,
+            _ => ExceptionKind::SError,
+        }
+    }
+}
+
+impl ExceptionOrigin {
+    fn from_vector(vector: u64) -> Self {
+        match vector / 4 {
+            0 => ExceptionOrigin::CurrentElSp0,
+            1 => ExceptionOrigin::CurrentElSpx,
+            2 => ExceptionOrigin::LowerElAarch64,
+            _ => ExceptionOrigin::LowerElAarch32,
+        }
+    }
+}
+
+impl fmt::Display for ExceptionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ExceptionKind::Synchronous => "synchronous",
+            ExceptionKind::Irq => "IRQ",
+            ExceptionKind::Fiq => "FIQ",
+            ExceptionKind::SError => "SError",
+        })
+    }
+}
+
+impl fmt::Display for ExceptionOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ExceptionOrigin::CurrentElSp0 => "current EL with SP_EL0",
+            ExceptionOrigin::CurrentElSpx => "current EL with SP_ELx",
+            ExceptionOrigin::LowerElAarch64 => "lower EL (AArch64)",
+            ExceptionOrigin::LowerElAarch32 => "lower EL (AArch32)",
+        })
+    }
+}
+
+/// Raw value of the exception syndrome register (`ESR_ELx`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Esr(pub u64);
+
+impl Esr {
+    /// The exception class, bits [31:26].
+    pub fn class(self) -> ExceptionClass {
+        ExceptionClass::from(((self.0 >> 26) & 0x3f) as u8)
+    }
+
+    /// Whether the trapped instruction was 32 bits long, bit 25.
+    pub fn is_32bit_instruction(self) -> bool {
+        self.0 & (1 << 25) != 0
+    }
+
+    /// The instruction specific syndrome, bits [24:0].
+    pub fn iss(self) -> u32 {
+        (self.0 & 0x1ff_ffff) as u32
+    }
+}
+
+/// Exception class field of `ESR_ELx`. Only the classes that can be taken in AArch64 have a name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionClass {
+    /// Unknown reason, such as an undefined instruction.
+    Unknown,
+    /// Trapped `wfi` or `wfe` instruction.
+    TrappedWfx,
+    /// Trapped access to SIMD or floating point registers.
+    TrappedFpSimd,
+    /// Illegal execution state.
+    IllegalExecutionState,
+    /// `svc` instruction.
+    Svc,
+    /// `hvc` instruction.
+    Hvc,
+    /// `smc` instruction.
+    Smc,
+    /// Trapped `msr`, `mrs` or system instruction.
+    TrappedSystemRegister,
+    /// Instruction abort from a lower exception level.
+    InstructionAbortLowerEl,
+    /// Instruction abort from the same exception level.
+    InstructionAbortSameEl,
+    /// Misaligned program counter.
+    PcAlignment,
+    /// Data abort from a lower exception level.
+    DataAbortLowerEl,
+    /// Data abort from the same exception level.
+    DataAbortSameEl,
+    /// Misaligned stack pointer.
+    SpAlignment,
+    /// Trapped floating point exception.
+    TrappedFp,
+    /// System error.
+    SError,
+    /// Breakpoint from a lower exception level.
+    BreakpointLowerEl,
+    /// Breakpoint from the same exception level.
+    BreakpointSameEl,
+    /// Software step from a lower exception level.
+    SoftwareStepLowerEl,
+    /// Software step from the same exception level.
+    SoftwareStepSameEl,
+    /// Watchpoint from a lower exception level.
+    WatchpointLowerEl,
+    /// Watchpoint from the same exception level.
+    WatchpointSameEl,
+    /// `brk` instruction.
+    Brk,
+    /// Any other exception class.
+    Other(u8),
+}
+
+impl From<u8> for ExceptionClass {
+    fn from(ec: u8) -> Self {
+        use ExceptionClass::*;
+
+        match ec {
+            0x00 => Unknown,
+            0x01 => TrappedWfx,
+            0x07 => TrappedFpSimd,
+            0x0e => IllegalExecutionState,
+            0x15 => Svc,
+            0x16 => Hvc,
+            0x17 => Smc,
+            0x18 => TrappedSystemRegister,
+            0x20 => InstructionAbortLowerEl,
+            0x21 => InstructionAbortSameEl,
+            0x22 => PcAlignment,
+            0x24 => DataAbortLowerEl,
+            0x25 => DataAbortSameEl,
+            0x26 => SpAlignment,
+            0x2c => TrappedFp,
+            0x2f => SError,
+            0x30 => BreakpointLowerEl,
+            0x31 => BreakpointSameEl,
+            0x32 => SoftwareStepLowerEl,
+            0x33 => SoftwareStepSameEl,
+            0x34 => WatchpointLowerEl,
+            0x35 => WatchpointSameEl,
+            0x3c => Brk,
+            other => Other(other),
+        }
+    }
+}
+
+/// Fault status code of instruction and data aborts (`IFSC`/`DFSC`, ISS bits [5:0]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultStatus {
+    /// Address size fault at the given translation table level.
+    AddressSize(u8),
+    /// Translation fault at the given translation table level.
+    Translation(u8),
+    /// Access flag fault at the given translation table level.
+    AccessFlag(u8),
+    /// Permission fault at the given translation table level.
+    Permission(u8),
+    /// Synchronous external abort.
+    SynchronousExternal,
+    /// Alignment fault.
+    Alignment,
+    /// TLB conflict abort.
+    TlbConflict,
+    /// Any other fault status code.
+    Other(u8),
+}
+
+impl From<u8> for FaultStatus {
+    fn from(fsc: u8) -> Self {
+        let level = fsc & 0b11;
+        match fsc {
+            0b000000..=0b000011 => FaultStatus::AddressSize(level),
+            0b000100..=0b000111 => FaultStatus::Translation(level),
+            0b001000..=0b001011 => FaultStatus::AccessFlag(level),
+            0b001100..=0b001111 => FaultStatus::Permission(level),
+            0b010000 => FaultStatus::SynchronousExternal,
+            0b100001 => FaultStatus::Alignment,
+            0b110000 => FaultStatus::TlbConflict,
+            other => FaultStatus::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultStatus::AddressSize(level) => write!(f, "address size fault, level {}", level),
+            FaultStatus::Translation(level) => write!(f, "translation fault, level {}", level),
+            FaultStatus::AccessFlag(level) => write!(f, "access flag fault, level {}", level),
+            FaultStatus::Permission(level) => write!(f, "permission fault, level {}", level),
+            FaultStatus::SynchronousExternal => f.write_str("synchronous external abort"),
+            FaultStatus::Alignment => f.write_str("alignment fault"),
+            FaultStatus::TlbConflict => f.write_str("TLB conflict abort"),
+            FaultStatus::Other(fsc) => write!(f, "fault status {:#08b}", fsc),
+        }
+    }
+}
+
+/// Decoded exception syndrome.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syndrome {
+    /// Data abort, for example caused by a load or store to an unmapped address.
+    DataAbort {
+        /// Whether the abort was taken from the same exception level.
+        same_el: bool,
+        /// What caused the abort.
+        fault: FaultStatus,
+        /// Whether the abort was caused by a write, as opposed to a read.
+        write: bool,
+        /// Faulting virtual address, if `FAR_ELx` is valid.
+        far: Option<u64>,
+    },
+    /// Instruction abort, for example caused by jumping to an unmapped address.
+    InstructionAbort {
+        /// Whether the abort was taken from the same exception level.
+        same_el: bool,
+        /// What caused the abort.
+        fault: FaultStatus,
+        /// Faulting virtual address, if `FAR_ELx` is valid.
+        far: Option<u64>,
+    },
+    /// Misaligned program counter. The faulting address is the value of `FAR_ELx`.
+    PcAlignment(u64),
+    /// Misaligned stack pointer.
+    SpAlignment,
+    /// Supervisor call with the given immediate.
+    Svc(u16),
+    /// Hypervisor call with the given immediate.
+    Hvc(u16),
+    /// Secure monitor call with the given immediate.
+    Smc(u16),
+    /// Breakpoint instruction with the given immediate.
+    Brk(u16),
+    /// Illegal execution state, for example an `eret` to an invalid mode.
+    IllegalExecutionState,
+    /// Unknown reason, usually an undefined instruction.
+    Unknown,
+    /// Any other exception class, with its raw instruction specific syndrome.
+    Other {
+        /// The exception class.
+        class: ExceptionClass,
+        /// The instruction specific syndrome.
+        iss: u32,
+    },
+}
+
+impl Syndrome {
+    /// Decodes the syndrome of an exception from the values of `ESR_ELx` and `FAR_ELx`.
+    pub fn decode(esr: Esr, far: u64) -> Self {
+        let iss = esr.iss();
+        let imm16 = (iss & 0xffff) as u16;
+        let fault = FaultStatus::from((iss & 0x3f) as u8);
+        // FnV, ISS bit 10: when set `FAR_ELx` is not valid.
+        let far_valid = (iss & (1 << 10) == 0).then(|| far);
+
+        match esr.class() {
+            class @ (ExceptionClass::DataAbortLowerEl | ExceptionClass::DataAbortSameEl) => {
+                Syndrome::DataAbort {
+                    same_el: class == ExceptionClass::DataAbortSameEl,
+                    fault,
+                    // WnR, ISS bit 6.
+                    write: iss & (1 << 6) != 0,
+                    far: far_valid,
+                }
+            }
+            class @ (ExceptionClass::InstructionAbortLowerEl
+            | ExceptionClass::InstructionAbortSameEl) => Syndrome::InstructionAbort {
+                same_el: class == ExceptionClass::InstructionAbortSameEl,
+                fault,
+                far: far_valid,
+            },
+            ExceptionClass::PcAlignment => Syndrome::PcAlignment(far),
+            ExceptionClass::SpAlignment => Syndrome::SpAlignment,
+            ExceptionClass::Svc => Syndrome::Svc(imm16),
+            ExceptionClass::Hvc => Syndrome::Hvc(imm16),
+            ExceptionClass::Smc => Syndrome::Smc(imm16),
+            ExceptionClass::Brk => Syndrome::Brk(imm16),
+            ExceptionClass::IllegalExecutionState => Syndrome::IllegalExecutionState,
+            ExceptionClass::Unknown => Syndrome::Unknown,
+            class => Syndrome::Other { class, iss },
+        }
+    }
+}
+
+impl fmt::Display for Syndrome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn el(same_el: bool) -> &'static str {
+            if same_el {
+                "same EL"
+            } else {
+                "lower EL"
+            }
+        }
+
+        match *self {
+            Syndrome::DataAbort {
+                same_el,
+                fault,
+                write,
+                far,
+            } => {
+                let access = if write { "write" } else { "read" };
+                write!(f, "data abort ({}) on {}: {}", el(same_el), access, fault)?;
+                match far {
+                    Some(far) => write!(f, " at {:#018x}", far),
+                    None => f.write_str(" at unknown address"),
+                }
+            }
+            Syndrome::InstructionAbort {
+                same_el,
+                fault,
+                far,
+            } => {
+                write!(f, "instruction abort ({}): {}", el(same_el), fault)?;
+                match far {
+                    Some(far) => write!(f, " at {:#018x}", far),
+                    None => f.write_str(" at unknown address"),
+                }
+            }
+            Syndrome::PcAlignment(far) => write!(f, "misaligned PC {:#018x}", far),
+            Syndrome::SpAlignment => f.write_str("misaligned SP"),
+            Syndrome::Svc(imm) => write!(f, "svc #{:#x}", imm),
+            Syndrome::Hvc(imm) => write!(f, "hvc #{:#x}", imm),
+            Syndrome::Smc(imm) => write!(f, "smc #{:#x}", imm),
+            Syndrome::Brk(imm) => write!(f, "brk #{:#x}", imm),
+            Syndrome::IllegalExecutionState => f.write_str("illegal execution state"),
+            Syndrome::Unknown => f.write_str("unknown reason (undefined instruction?)"),
+            Syndrome::Other { class, iss } => write!(f, "{:?} (ISS {:#09x})", class, iss),
+        }
+    }
+}
+
+/// Rust entry point of every exception, called from the vector table in `vectors.S`. `vector` is
+/// the index of the entry that was taken, between 0 and 15.
+#[no_mangle]
+unsafe extern "C" fn _exception_handler(frame: &mut ExceptionFrame, vector: u64) {
+    let kind = ExceptionKind::from_vector(vector);
+    let origin = ExceptionOrigin::from_vector(vector);
+
+    if kind == ExceptionKind::Irq {
+        crate::irq::dispatch();
+        return;
+    }
+
+    report(frame, kind, origin);
+    match kind {
+        ExceptionKind::Synchronous => panic!("unhandled exception: {}", frame.syndrome()),
+        kind => panic!("unhandled {} from {}", kind, origin),
+    }
+}
+
+/// Prints a crash report for an unhandled exception.
+fn report(frame: &ExceptionFrame, kind: ExceptionKind, origin: ExceptionOrigin) {
+    let esr = Esr(frame.esr);
+    crate::println!(
+        "\n*** unhandled {} exception from {}, taken to EL{} on core {}",
+        kind,
+        origin,
+        get_current_exception_level(),
+        get_cpu()
+    );
+    if kind == ExceptionKind::Synchronous {
+        crate::println!(
+            "      class: {:?} (EC {:#04x}), ISS: {:#09x}",
+            esr.class(),
+            (frame.esr >> 26) & 0x3f,
+            esr.iss()
+        );
+        crate::println!("      cause: {}", frame.syndrome());
+    }
+    crate::println!("{}", frame);
+    crate::println!("backtrace:");
+    crate::println!("  at   {}", crate::backtrace::Location(frame.elr as usize));
+    crate::backtrace::print_from(frame.gpr[29] as usize);
+}
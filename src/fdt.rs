@@ -0,0 +1,226 @@
+//! Minimal reader of flattened device trees (FDT), enough to find the memory map.
+//!
+//! The firmware passes the address of the device tree blob in `x0`. It is big endian, starts with
+//! a [`Header`], and contains a memory reservation block and a structure block, a stream of tokens
+//! describing the nodes and their properties.
+
+use core::fmt;
+
+use crate::error::{Error, ErrorKind};
+use crate::memory::{align_down, mmu};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Size of the header, up to `size_dt_struct`.
+const HEADER_SIZE: usize = 40;
+
+/// Errors when reading a device tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    /// There is no device tree at the address.
+    BadMagic,
+    /// An offset or length points outside of the blob.
+    Truncated,
+    /// The structure block has an unknown token.
+    BadToken(u32),
+    /// Part of the blob is not mapped.
+    Unmapped,
+}
+
+impl fmt::Display for FdtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FdtError::BadMagic => f.write_str("no device tree found"),
+            FdtError::Truncated => f.write_str("device tree is truncated"),
+            FdtError::BadToken(token) => write!(f, "bad device tree token {:#x}", token),
+            FdtError::Unmapped => f.write_str("device tree is not mapped"),
+        }
+    }
+}
+
+impl Error for FdtError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            FdtError::BadMagic => ErrorKind::NotFound,
+            _ => ErrorKind::InvalidArgument,
+        }
+    }
+}
+
+/// A region of physical memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    /// Start address.
+    pub base: u64,
+    /// Size in bytes.
+    pub size: u64,
+}
+
+/// A device tree blob.
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    struct_off: usize,
+    strings_off: usize,
+    rsvmap_off: usize,
+}
+
+fn be32(blob: &[u8], off: usize) -> Result<u32, FdtError> {
+    let bytes = blob.get(off..off + 4).ok_or(FdtError::Truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn be64(blob: &[u8], off: usize) -> Result<u64, FdtError> {
+    Ok((be32(blob, off)? as u64) << 32 | be32(blob, off + 4)? as u64)
+}
+
+/// Reads a number of `cells` 32 bit cells.
+fn cells(blob: &[u8], off: usize, cells: u32) -> Result<u64, FdtError> {
+    (0..cells as usize).try_fold(0, |acc, i| Ok(acc << 32 | be32(blob, off + 4 * i)? as u64))
+}
+
+/// Gets the null terminated string at `off`.
+fn c_str(blob: &[u8], off: usize) -> Result<&[u8], FdtError> {
+    let rest = blob.get(off..).ok_or(FdtError::Truncated)?;
+    let len = rest
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or(FdtError::Truncated)?;
+    Ok(&rest[..len])
+}
+
+const fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+/// Checks that every page of `addr..addr + size` is mapped, if the MMU is enabled.
+fn check_mapped(addr: usize, size: usize) -> Result<(), FdtError> {
+    if !mmu::is_enabled() {
+        return Ok(());
+    }
+    let end = addr.checked_add(size).ok_or(FdtError::Truncated)?;
+    let mut page = align_down(addr, mmu::PAGE_SIZE);
+    while page < end {
+        mmu::translate(page).ok_or(FdtError::Unmapped)?;
+        page += mmu::PAGE_SIZE;
+    }
+    Ok(())
+}
+
+impl<'a> Fdt<'a> {
+    /// Reads the device tree at `addr`.
+    ///
+    /// # Safety
+    ///
+    /// If there is a device tree header at `addr`, the whole blob must not change for `'a`. Without
+    /// the MMU, the whole blob must also be readable, otherwise only mapped pages are read.
+    pub unsafe fn from_addr(addr: usize) -> Result<Self, FdtError> {
+        // The first page is never mapped, so that null pointers fault.
+        if addr < mmu::PAGE_SIZE || addr % 4 != 0 {
+            return Err(FdtError::BadMagic);
+        }
+        check_mapped(addr, HEADER_SIZE)?;
+        let header = core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE);
+        if be32(header, 0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = be32(header, 4)? as usize;
+        check_mapped(addr, total_size)?;
+        let blob = core::slice::from_raw_parts(addr as *const u8, total_size.max(HEADER_SIZE));
+        let fdt = Fdt {
+            blob,
+            struct_off: be32(blob, 8)? as usize,
+            strings_off: be32(blob, 12)? as usize,
+            rsvmap_off: be32(blob, 16)? as usize,
+        };
+        if fdt.struct_off >= total_size || fdt.strings_off > total_size {
+            return Err(FdtError::Truncated);
+        }
+        Ok(fdt)
+    }
+
+    /// Address and size of the blob itself.
+    pub fn region(&self) -> Region {
+        Region {
+            base: self.blob.as_ptr() as u64,
+            size: self.blob.len() as u64,
+        }
+    }
+
+    /// Calls `f` with every entry of the memory reservation block.
+    pub fn for_each_reserved(&self, mut f: impl FnMut(Region)) -> Result<(), FdtError> {
+        let mut off = self.rsvmap_off;
+        loop {
+            let region = Region {
+                base: be64(self.blob, off)?,
+                size: be64(self.blob, off + 8)?,
+            };
+            if region.size == 0 {
+                return Ok(());
+            }
+            f(region);
+            off += 16;
+        }
+    }
+
+    /// Calls `f` with every region in the `reg` property of the `/memory` nodes.
+    pub fn for_each_memory(&self, mut f: impl FnMut(Region)) -> Result<(), FdtError> {
+        // The defaults from the specification, until the root node says otherwise.
+        let mut address_cells = 2;
+        let mut size_cells = 1;
+        let mut depth = 0;
+        let mut in_memory = false;
+
+        let mut off = self.struct_off;
+        loop {
+            let token = be32(self.blob, off)?;
+            off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(self.blob, off)?;
+                    off = align4(off + name.len() + 1);
+                    depth += 1;
+                    in_memory = depth == 2 && (name == b"memory" || name.starts_with(b"memory@"));
+                }
+                FDT_END_NODE => {
+                    depth -= 1;
+                    in_memory = false;
+                }
+                FDT_PROP => {
+                    let len = be32(self.blob, off)? as usize;
+                    let name = c_str(
+                        self.blob,
+                        self.strings_off + be32(self.blob, off + 4)? as usize,
+                    )?;
+                    let value = off + 8;
+                    off = align4(value + len);
+                    if depth == 1 && name == b"#address-cells" {
+                        address_cells = be32(self.blob, value)?;
+                    } else if depth == 1 && name == b"#size-cells" {
+                        size_cells = be32(self.blob, value)?;
+                    } else if in_memory && name == b"reg" {
+                        let entry = 4 * (address_cells + size_cells) as usize;
+                        for entry_off in (value..value + len).step_by(entry.max(4)) {
+                            f(Region {
+                                base: cells(self.blob, entry_off, address_cells)?,
+                                size: cells(
+                                    self.blob,
+                                    entry_off + 4 * address_cells as usize,
+                                    size_cells,
+                                )?,
+                            });
+                        }
+                    }
+                }
+                FDT_NOP => (),
+                FDT_END => return Ok(()),
+                token => return Err(FdtError::BadToken(token)),
+            }
+        }
+    }
+}
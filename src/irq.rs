@@ -0,0 +1,284 @@
+//! Interrupt handling.
+//!
+//! Interrupts come from two controllers: the BCM2835 interrupt controller for the peripherals,
+//! which routes all of them to a single core, and the ARM local interrupt controller, which has the
+//! interrupts private to each core, like their generic timers and mailboxes. Handlers are
+//! registered per [`IrqSource`] and called by [`dispatch`] from the IRQ exception vector, with
+//! interrupts masked.
+//!
+//! Handlers must acknowledge the interrupt at the device that raised it, otherwise it fires again
+//! as soon as the handler returns.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::drivers::interrupt_controller::{self, NUM_PERIPHERAL_IRQS};
+use crate::drivers::local_intc::{self, CoreTimer, LOCAL_IRQ_GPU};
+use crate::error::{Error, ErrorKind};
+use crate::memory::mmu::MapError;
+use crate::smp;
+use crate::utils::get_cpu;
+
+/// An interrupt handler.
+pub type IrqHandler = fn();
+
+/// Number of interrupt sources of the ARM local interrupt controller.
+const NUM_LOCAL_IRQS: usize = 12;
+
+/// The interrupt sources of the ARM local interrupt controller, which are private to each core.
+/// The values are their bits in the interrupt source register of each core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LocalIrq {
+    /// Secure physical timer.
+    PhysicalSecureTimer = 0,
+    /// Non-secure physical timer.
+    PhysicalTimer = 1,
+    /// Hypervisor physical timer.
+    HypervisorTimer = 2,
+    /// Virtual timer.
+    VirtualTimer = 3,
+    /// Mailbox 0.
+    Mailbox0 = 4,
+    /// Mailbox 1.
+    Mailbox1 = 5,
+    /// Mailbox 2.
+    Mailbox2 = 6,
+    /// Mailbox 3.
+    Mailbox3 = 7,
+    /// Performance monitors.
+    Pmu = 9,
+    /// AXI outstanding transactions.
+    Axi = 10,
+    /// The local timer.
+    LocalTimer = 11,
+}
+
+/// A source of interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    /// One of the 64 peripheral interrupts of the BCM2835 interrupt controller.
+    Peripheral(u8),
+    /// An interrupt private to each core.
+    Local(LocalIrq),
+}
+
+impl IrqSource {
+    /// System timer compare 1.
+    pub const SYSTEM_TIMER_1: Self = IrqSource::Peripheral(1);
+    /// System timer compare 3.
+    pub const SYSTEM_TIMER_3: Self = IrqSource::Peripheral(3);
+    /// The auxiliary peripherals, including the mini UART.
+    pub const AUX: Self = IrqSource::Peripheral(29);
+    /// GPIO bank 0.
+    pub const GPIO_0: Self = IrqSource::Peripheral(49);
+    /// GPIO bank 1.
+    pub const GPIO_1: Self = IrqSource::Peripheral(50);
+    /// The PL011 UART.
+    pub const PL011: Self = IrqSource::Peripheral(57);
+}
+
+impl fmt::Display for IrqSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IrqSource::Peripheral(irq) => write!(f, "peripheral IRQ {}", irq),
+            IrqSource::Local(irq) => write!(f, "local IRQ {:?}", irq),
+        }
+    }
+}
+
+/// Errors that can happen when registering a handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The peripheral interrupt doesn't exist.
+    InvalidSource,
+    /// A handler is already registered for the source.
+    AlreadyRegistered,
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            IrqError::InvalidSource => "invalid interrupt source",
+            IrqError::AlreadyRegistered => "a handler is already registered for the source",
+        })
+    }
+}
+
+impl Error for IrqError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            IrqError::InvalidSource => ErrorKind::InvalidArgument,
+            IrqError::AlreadyRegistered => ErrorKind::AlreadyExists,
+        }
+    }
+}
+
+/// Registered handlers, stored as `usize` so they can be atomic. Zero means no handler.
+static PERIPHERAL_HANDLERS: [AtomicUsize; NUM_PERIPHERAL_IRQS] =
+    [const { AtomicUsize::new(0) }; NUM_PERIPHERAL_IRQS];
+static LOCAL_HANDLERS: [AtomicUsize; NUM_LOCAL_IRQS] =
+    [const { AtomicUsize::new(0) }; NUM_LOCAL_IRQS];
+
+/// Number of interrupts without a handler. Their sources get disabled.
+static SPURIOUS: AtomicUsize = AtomicUsize::new(0);
+
+fn handler_slot(source: IrqSource) -> Result<&'static AtomicUsize, IrqError> {
+    match source {
+        IrqSource::Peripheral(irq) => PERIPHERAL_HANDLERS
+            .get(irq as usize)
+            .ok_or(IrqError::InvalidSource),
+        IrqSource::Local(irq) => Ok(&LOCAL_HANDLERS[irq as usize]),
+    }
+}
+
+/// Maps the ARM local interrupt controller, disables every peripheral interrupt and routes them to
+/// the boot core. Must be called by the boot core before the other cores are started.
+pub fn init() -> Result<(), MapError> {
+    local_intc::init()?;
+    interrupt_controller::disable_all();
+    local_intc::route_gpu_irqs(smp::boot_core());
+    Ok(())
+}
+
+/// Registers `handler` for `source`. The source still needs to be enabled with [`enable`].
+pub fn register(source: IrqSource, handler: IrqHandler) -> Result<(), IrqError> {
+    handler_slot(source)?
+        .compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| IrqError::AlreadyRegistered)
+}
+
+/// Disables `source` and removes its handler.
+pub fn unregister(source: IrqSource) -> Result<(), IrqError> {
+    let slot = handler_slot(source)?;
+    disable(source);
+    slot.store(0, Ordering::Release);
+    Ok(())
+}
+
+/// Enables `source`. Local sources are only enabled for the current core.
+pub fn enable(source: IrqSource) {
+    set_enabled(source, true)
+}
+
+/// Disables `source`. Local sources are only disabled for the current core.
+pub fn disable(source: IrqSource) {
+    set_enabled(source, false)
+}
+
+fn set_enabled(source: IrqSource, enabled: bool) {
+    match source {
+        IrqSource::Peripheral(irq) if (irq as usize) < NUM_PERIPHERAL_IRQS => {
+            if enabled {
+                interrupt_controller::enable(irq)
+            } else {
+                interrupt_controller::disable(irq)
+            }
+        }
+        IrqSource::Peripheral(_) => (),
+        IrqSource::Local(irq) => without_interrupts(|| {
+            let cpu = get_cpu() as usize;
+            // SAFETY: The control registers of the current core are only modified by this core,
+            // and interrupts are masked.
+            unsafe {
+                match irq as u8 {
+                    timer @ 0..=3 => {
+                        let timer = match timer {
+                            0 => CoreTimer::PhysicalSecure,
+                            1 => CoreTimer::PhysicalNonSecure,
+                            2 => CoreTimer::Hypervisor,
+                            _ => CoreTimer::Virtual,
+                        };
+                        local_intc::set_timer_irq(cpu, timer, enabled)
+                    }
+                    mailbox @ 4..=7 => {
+                        local_intc::set_mailbox_irq(cpu, mailbox as usize - 4, enabled)
+                    }
+                    // The remaining sources are shared by all cores and not supported yet.
+                    _ => (),
+                }
+            }
+        }),
+    }
+}
+
+/// Routes the peripheral interrupts to `cpu`.
+pub fn route_peripherals_to(cpu: usize) {
+    local_intc::route_gpu_irqs(cpu)
+}
+
+/// Gets the number of interrupts that fired without a registered handler.
+pub fn spurious_count() -> usize {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Unmasks IRQs on the current core.
+#[inline(always)]
+pub fn local_enable() {
+    unsafe { core::arch::asm!("msr daifclr, #2") };
+}
+
+/// Masks IRQs on the current core.
+#[inline(always)]
+pub fn local_disable() {
+    unsafe { core::arch::asm!("msr daifset, #2") };
+}
+
+/// Whether IRQs are unmasked on the current core.
+#[inline(always)]
+pub fn are_enabled() -> bool {
+    let daif: u64;
+    unsafe { core::arch::asm!("mrs {}, DAIF", out(reg) daif) };
+    daif & (1 << 7) == 0
+}
+
+/// Runs `f` with IRQs masked on the current core, restoring the previous state afterwards.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = are_enabled();
+    local_disable();
+    let ret = f();
+    if enabled {
+        local_enable();
+    }
+    ret
+}
+
+/// Calls the handler of `slot`, or disables `source` if there is none so that it doesn't keep
+/// firing.
+fn call(slot: &AtomicUsize, source: IrqSource) {
+    match slot.load(Ordering::Acquire) {
+        0 => {
+            SPURIOUS.fetch_add(1, Ordering::Relaxed);
+            disable(source);
+            log::warn!("no handler for {}, disabled it", source);
+        }
+        // SAFETY: Only `IrqHandler`s are stored in the handler tables.
+        handler => unsafe { core::mem::transmute::<usize, IrqHandler>(handler)() },
+    }
+}
+
+/// Calls the handlers of every pending interrupt of the current core. Called from the IRQ vector.
+pub fn dispatch() {
+    let mut sources = local_intc::irq_source(get_cpu() as usize);
+    while sources != 0 {
+        let bit = sources.trailing_zeros();
+        sources &= sources - 1;
+
+        if bit == LOCAL_IRQ_GPU {
+            let mut pending = interrupt_controller::pending();
+            while pending != 0 {
+                let irq = pending.trailing_zeros() as usize;
+                pending &= pending - 1;
+                call(&PERIPHERAL_HANDLERS[irq], IrqSource::Peripheral(irq as u8));
+            }
+        } else if let Some(slot) = LOCAL_HANDLERS.get(bit as usize) {
+            // SAFETY: `LocalIrq` values are the bits of the source register, and bit 8 is handled
+            // above.
+            let irq = unsafe { core::mem::transmute::<u8, LocalIrq>(bit as u8) };
+            call(slot, IrqSource::Local(irq));
+        }
+    }
+}
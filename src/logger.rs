@@ -0,0 +1,160 @@
+//! Kernel logger, the backend of the [`log`] crate macros.
+//!
+//! Records are kept in the [`crate::dmesg`] buffer and printed to the console, prefixed with the
+//! time since boot, the level and the core that emitted them:
+//!
+//! ```text
+//! [    0.012345 INFO  core 0 rasp3_os::smp] message
+//! ```
+//!
+//! The maximum level compiled in is set through the features of the `log` dependency, so the
+//! disabled levels cost nothing. At runtime records are filtered by the level of the longest
+//! module prefix set with [`set_module_level`], or else by the default level.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use crate::irq;
+use crate::time::Instant;
+use crate::utils::get_cpu;
+
+/// Maximum number of module filters.
+const MAX_MODULE_FILTERS: usize = 8;
+
+/// Level used for the modules without a filter.
+const DEFAULT_LEVEL: LevelFilter = if cfg!(debug_assertions) {
+    LevelFilter::Debug
+} else {
+    LevelFilter::Info
+};
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+/// Level of the modules without a filter, as a `LevelFilter` cast to `usize`.
+static DEFAULT_FILTER: AtomicUsize = AtomicUsize::new(DEFAULT_LEVEL as usize);
+
+/// Module prefixes and their levels.
+static MODULE_FILTERS: spin::Mutex<[Option<(&'static str, LevelFilter)>; MAX_MODULE_FILTERS]> =
+    spin::Mutex::new([None; MAX_MODULE_FILTERS]);
+
+fn level_from_usize(val: usize) -> LevelFilter {
+    match val {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Installs the kernel logger. Records logged before any console is registered are kept in the
+/// early console buffer.
+pub fn init() -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(DEFAULT_LEVEL);
+    Ok(())
+}
+
+/// Sets the level of the modules without a filter.
+pub fn set_default_level(level: LevelFilter) {
+    DEFAULT_FILTER.store(level as usize, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Sets the level of the modules whose path starts with `prefix`, like `rasp3_os::smp`. Returns
+/// `false` if there are already too many filters.
+pub fn set_module_level(prefix: &'static str, level: LevelFilter) -> bool {
+    let set = irq::without_interrupts(|| {
+        let mut filters = MODULE_FILTERS.lock();
+        let slot = filters
+            .iter()
+            .position(|f| matches!(f, Some((p, _)) if *p == prefix))
+            .or_else(|| filters.iter().position(Option::is_none));
+        match slot {
+            Some(idx) => {
+                filters[idx] = Some((prefix, level));
+                true
+            }
+            None => false,
+        }
+    });
+    update_max_level();
+    set
+}
+
+/// Updates the global level used by the `log` macros to skip records early, to the most verbose
+/// level of any module.
+fn update_max_level() {
+    let max = irq::without_interrupts(|| {
+        MODULE_FILTERS
+            .lock()
+            .iter()
+            .flatten()
+            .map(|&(_, level)| level)
+            .fold(
+                level_from_usize(DEFAULT_FILTER.load(Ordering::Relaxed)),
+                Ord::max,
+            )
+    });
+    log::set_max_level(max);
+}
+
+/// Gets the level of `target`.
+fn level_for(target: &str) -> LevelFilter {
+    irq::without_interrupts(|| {
+        MODULE_FILTERS
+            .lock()
+            .iter()
+            .flatten()
+            .filter(|(prefix, _)| target.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|&(_, level)| level)
+    })
+    .unwrap_or_else(|| level_from_usize(DEFAULT_FILTER.load(Ordering::Relaxed)))
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = Instant::now();
+        let cpu = get_cpu();
+        crate::dmesg::write(
+            record.level(),
+            cpu as u8,
+            now.since_boot(),
+            format_args!("{}: {}", record.target(), record.args()),
+        );
+
+        let level = match record.level() {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        let since_boot = now.since_boot();
+        crate::println!(
+            "[{:>5}.{:06} {} core {} {}] {}",
+            since_boot.as_secs(),
+            since_boot.subsec_micros(),
+            level,
+            cpu,
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        crate::console::flush();
+    }
+}
@@ -15,65 +15,120 @@
     const_mut_refs,
     bench_black_box,
     decl_macro,
-    inline_const
+    inline_const,
+    alloc_error_handler
 )]
 #![allow(dead_code, unused_imports)]
 
+extern crate alloc;
+
 mod allocators;
+mod backtrace;
 mod boot;
+mod console;
+mod dmesg;
 mod drivers;
 mod error;
+mod exception;
+mod fdt;
+mod irq;
+mod logger;
+mod memory;
+mod panic;
 mod print;
+mod smp;
+mod time;
 mod utils;
 
-use core::{panic::PanicInfo, sync::atomic::Ordering};
+use core::{alloc::Layout, panic::PanicInfo, sync::atomic::Ordering};
 
-use drivers::{mu_is_setup, mu_print, mu_println, mu_recv, mu_send, MiniUART, GPIO};
+use drivers::{framebuffer, mailbox, Pins};
 use error::KError;
+use log::{info, warn};
 use utils::{get_cpu, get_current_exception_level};
 
-unsafe fn kernel_init() -> ! {
-    // This scope is necessary because the GPIO and Mini UART are beeing acquired and will be
-    // release only once dropped, which happens at the end of the scope.
-    {
-        let mut gpio = GPIO::acquire();
-        let mut mini_uart = MiniUART::acquire();
-        mini_uart.init_default(&mut gpio);
+/// Resolution of the framebuffer console.
+const FRAMEBUFFER_WIDTH: u32 = 640;
+const FRAMEBUFFER_HEIGHT: u32 = 480;
+/// Delay before the board is reset after a panic, with the `reboot-on-panic` feature.
+const PANIC_REBOOT_DELAY: time::Duration = time::Duration::from_secs(5);
+
+unsafe fn kernel_init(dtb: usize) -> ! {
+    logger::init().expect("failed to install the logger");
+    let mut pins = Pins::take().expect("the GPIO pins were already taken");
+    console::init_uart(&mut pins).expect("failed to register the console");
+    pins.store();
+    #[cfg(feature = "framebuffer-console")]
+    if let Err(e) = framebuffer::init_console(FRAMEBUFFER_WIDTH, FRAMEBUFFER_HEIGHT) {
+        warn!("no framebuffer console: {:#}", e);
     }
+    memory::frame::init(dtb);
+    smp::init().expect("failed to unmap the core stack guard pages");
+    irq::init().expect("failed to map the ARM local peripherals");
+    time::init().expect("failed to register the timer interrupt");
+    time::init_core();
+    panic::init().expect("failed to register the halt interrupt");
+    panic::init_core();
+    #[cfg(feature = "reboot-on-panic")]
+    panic::set_reboot_delay(Some(PANIC_REBOOT_DELAY));
+    drivers::system_timer::init().expect("failed to register the system timer interrupts");
+    console::init_uart_irq().expect("failed to register the console interrupt");
+    irq::local_enable();
 
     match kernel_main() {
-        Err(e) => panic!("{}", e),
+        Err(e) => panic!("{:#}", e),
         Ok(impossible) => impossible,
     }
 }
 
 fn kernel_main() -> Result<!, KError> {
-    mu_println!("Initializing kernel...");
-    mu_println!(
-        "[INFO] initialized in exception level {}",
+    println!("Initializing kernel...");
+    info!(
+        "initialized in exception level {}",
         get_current_exception_level()
     );
-    mu_println!("[INFO] core {:x}", get_cpu());
+    info!("core {:x}", get_cpu());
+    let heap = memory::heap::stats();
+    info!("heap of {} KiB", heap.size / 1024);
+    match (mailbox::board_revision(), mailbox::firmware_revision()) {
+        (Ok(board), Ok(firmware)) => info!("board revision {:#x}, firmware {}", board, firmware),
+        (Err(e), _) | (_, Err(e)) => warn!("failed to query the firmware: {}", e),
+    }
 
-    boot::CHILD_TASKS[1].store(hello_from_cpu as *mut (), Ordering::SeqCst);
-    boot::CHILD_TASKS[2].store(hello_from_cpu as *mut (), Ordering::SeqCst);
-    boot::CHILD_TASKS[3].store(hello_from_cpu as *mut (), Ordering::SeqCst);
+    for cpu in (0..smp::NUM_CORES).filter(|&cpu| cpu != smp::boot_core()) {
+        if let Err(e) = smp::start_core(cpu, boot::child_loop, cpu) {
+            warn!("failed to start core {}: {}", cpu, e);
+        }
+    }
 
-    cortex_a::asm::sev();
+    let mut hellos: [Option<smp::JoinHandle<u64>>; smp::NUM_CORES] = Default::default();
+    for cpu in (0..smp::NUM_CORES).filter(|&cpu| cpu != smp::boot_core()) {
+        match smp::spawn_on(cpu, hello_from_cpu) {
+            Ok(hello) => hellos[cpu] = Some(hello),
+            Err(e) => warn!("failed to say hello from core {}: {}", cpu, e),
+        }
+    }
+    for hello in hellos.into_iter().flatten() {
+        info!("core {} said hello", hello.join());
+    }
 
+    let mut buf = [0; 64];
     loop {
-        let byte = mu_recv();
-        match byte {
-            b'\r' => mu_send(b'\n'),
-            127 => mu_print!("\x08 \x08"),
-            byte => mu_send(byte),
+        let len = console::read(&mut buf);
+        for &byte in &buf[..len] {
+            match byte {
+                b'\r' => println!(),
+                127 => print!("\x08 \x08"),
+                byte => print!("{}", byte as char),
+            }
         }
     }
 }
 
 #[no_mangle]
-fn hello_from_cpu() {
-    mu_println!("Hello, from cpu {}", get_cpu());
+fn hello_from_cpu() -> u64 {
+    println!("Hello, from cpu {}", get_cpu());
+    get_cpu()
 }
 
 #[inline(never)]
@@ -84,11 +139,44 @@ fn marker() {
     cortex_a::asm::nop();
 }
 
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let stats = memory::heap::stats();
+    panic!(
+        "failed to allocate {} bytes aligned to {} ({} of {} heap bytes used)",
+        layout.size(),
+        layout.align(),
+        stats.used,
+        stats.size
+    );
+}
+
+/// Number of log records printed by the panic handler.
+const PANIC_DMESG_RECORDS: usize = 8;
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    if mu_is_setup() {
-        mu_println!("{}", info);
+    let unstopped = match panic::enter() {
+        panic::Entry::Report { unstopped } => unstopped,
+        panic::Entry::Nested => panic::finish(),
+    };
+    println!(
+        "\n*** kernel panic on core {} at EL{}",
+        get_cpu(),
+        get_current_exception_level()
+    );
+    println!("{}", info);
+    for cpu in (0..smp::NUM_CORES).filter(|cpu| unstopped & (1 << cpu) != 0) {
+        println!("core {} did not stop, output may be garbled", cpu);
+    }
+    println!("backtrace:");
+    backtrace::print();
+    println!("last log records:");
+    dmesg::dump_last(PANIC_DMESG_RECORDS);
+    if let Some(delay) = panic::reboot_delay() {
+        println!("rebooting in {} ms", delay.as_millis());
     }
+    console::flush();
     marker();
-    utils::inifinite_loop();
+    panic::finish();
 }
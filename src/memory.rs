@@ -0,0 +1,58 @@
+//! Memory management.
+
+pub mod frame;
+pub mod heap;
+pub mod mmu;
+
+/// Rounds `addr` up to the next multiple of `align`, which must be a power of two.
+#[inline(always)]
+pub const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Rounds `addr` down to the previous multiple of `align`, which must be a power of two.
+#[inline(always)]
+pub const fn align_down(addr: usize, align: usize) -> usize {
+    addr & !(align - 1)
+}
+
+/// Gets the size of the smallest data cache line, from `CTR_EL0.DminLine`.
+pub fn dcache_line_size() -> usize {
+    let ctr: u64;
+    unsafe { core::arch::asm!("mrs {}, CTR_EL0", out(reg) ctr) };
+    4 << ((ctr >> 16) & 0xf)
+}
+
+/// Cleans the data cache lines containing `addr..addr + size` to the point of coherency, so that
+/// observers that don't go through the caches, like devices or cores with their caches still off,
+/// see what this core wrote.
+pub fn clean_dcache_range(addr: usize, size: usize) {
+    let line = dcache_line_size();
+    let mut cur = align_down(addr, line);
+    unsafe {
+        core::arch::asm!("dsb ish");
+        while cur < addr + size {
+            core::arch::asm!("dc cvac, {}", in(reg) cur);
+            cur += line;
+        }
+        core::arch::asm!("dsb sy");
+    }
+}
+
+/// Invalidates the data cache lines containing `addr..addr + size`, so that this core sees what
+/// observers that don't go through the caches, like devices, wrote to memory.
+///
+/// # Safety
+///
+/// Dirty data in the lines is thrown away, so `addr` and `size` must be aligned to
+/// [`dcache_line_size`], and nothing else may use the range while it is invalidated.
+pub unsafe fn invalidate_dcache_range(addr: usize, size: usize) {
+    let line = dcache_line_size();
+    let mut cur = align_down(addr, line);
+    core::arch::asm!("dsb sy");
+    while cur < addr + size {
+        core::arch::asm!("dc ivac, {}", in(reg) cur);
+        cur += line;
+    }
+    core::arch::asm!("dsb sy");
+}
@@ -0,0 +1,254 @@
+//! Physical page frame allocator.
+//!
+//! Keeps one bit per 4 KiB frame of the first [`MAX_MEMORY`] bytes, set when the frame is used or
+//! doesn't exist. The bitmap starts cleared, so that it lives in `.bss` rather than in the kernel
+//! image. At boot every frame is marked used, then the memory regions reported by the device tree
+//! are freed, counting frames covered by overlapping regions once, and finally the memory of the
+//! kernel is reserved again: everything up to `__heap_end`, which covers the boot stack, the kernel
+//! image, `.bss` with the secondary core stacks and the translation tables, and the kernel heap.
+//! The device tree blob and its memory reservations are reserved as well.
+//!
+//! Without a device tree the ARM memory is asked to the firmware through the mailbox. If that fails
+//! too, the default split of the Raspberry Pi 3 firmware is assumed, with the ARM memory in
+//! `0..DEFAULT_ARM_MEMORY_END` and the rest given to the VideoCore.
+
+use core::ops::Range;
+
+use super::{align_down, align_up, mmu::PAGE_SIZE};
+use crate::drivers::mailbox;
+use crate::fdt::{Fdt, Region};
+use crate::irq;
+
+/// Amount of memory covered by the allocator, all the RAM of a Raspberry Pi 3.
+pub const MAX_MEMORY: usize = 0x4000_0000;
+/// End of the ARM memory with the default firmware configuration, 64 MiB for the VideoCore.
+pub const DEFAULT_ARM_MEMORY_END: usize = 0x3c00_0000;
+
+const FRAME_COUNT: usize = MAX_MEMORY / PAGE_SIZE;
+const WORD_BITS: usize = u64::BITS as usize;
+
+extern "C" {
+    static __heap_end: u8;
+}
+
+/// Usage of the physical memory, in frames.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Frames of usable memory.
+    pub total: usize,
+    /// Frames that are free.
+    pub free: usize,
+}
+
+impl FrameStats {
+    /// Frames in use.
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+struct FrameAllocator {
+    /// One bit per frame, set when it is used.
+    bitmap: [u64; FRAME_COUNT / WORD_BITS],
+    total: usize,
+    free: usize,
+    /// Frame where the next search starts.
+    hint: usize,
+}
+
+static FRAMES: spin::Mutex<FrameAllocator> = spin::Mutex::new(FrameAllocator {
+    bitmap: [0; FRAME_COUNT / WORD_BITS],
+    total: 0,
+    free: 0,
+    hint: 0,
+});
+
+impl FrameAllocator {
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / WORD_BITS] & 1 << (frame % WORD_BITS) != 0
+    }
+
+    fn set(&mut self, frames: Range<usize>, used: bool) {
+        for frame in frames {
+            let word = &mut self.bitmap[frame / WORD_BITS];
+            let bit = 1 << (frame % WORD_BITS);
+            if (*word & bit != 0) != used {
+                *word ^= bit;
+                if used {
+                    self.free -= 1;
+                } else {
+                    self.free += 1;
+                }
+            }
+        }
+    }
+
+    /// Finds `count` free frames starting at a multiple of `align` frames.
+    fn find(&self, count: usize, align: usize) -> Option<usize> {
+        let search = |range: Range<usize>| {
+            let mut start = align_up(range.start, align);
+            while start + count <= range.end {
+                match (start..start + count).find(|&frame| self.is_used(frame)) {
+                    Some(used) => start = align_up(used + 1, align),
+                    None => return Some(start),
+                }
+            }
+            None
+        };
+        search(self.hint..FRAME_COUNT).or_else(|| search(0..FRAME_COUNT))
+    }
+}
+
+/// Converts a byte range to the range of frames fully inside it.
+fn inner_frames(base: usize, end: usize) -> Range<usize> {
+    align_up(base, PAGE_SIZE) / PAGE_SIZE..align_down(end, PAGE_SIZE) / PAGE_SIZE
+}
+
+/// Converts a byte range to the range of frames that overlap it.
+fn outer_frames(base: usize, end: usize) -> Range<usize> {
+    align_down(base, PAGE_SIZE) / PAGE_SIZE..align_up(end, PAGE_SIZE) / PAGE_SIZE
+}
+
+fn clamp(region: Region) -> (usize, usize) {
+    let end = region
+        .base
+        .saturating_add(region.size)
+        .min(MAX_MEMORY as u64);
+    (region.base.min(end) as usize, end as usize)
+}
+
+/// Builds the allocator from the memory map in the device tree at `dtb`, or from the ARM memory
+/// reported by the firmware if there is none. Must be called once, by the boot core.
+///
+/// # Safety
+///
+/// `dtb` must be the address passed by the firmware in `x0`, or zero.
+pub unsafe fn init(dtb: usize) {
+    let mut frames = FRAMES.lock();
+    frames.bitmap.fill(u64::MAX);
+    frames.free = 0;
+
+    let fdt = Fdt::from_addr(dtb);
+    let found = match &fdt {
+        Ok(fdt) => {
+            let mut found = false;
+            let res = fdt.for_each_memory(|region| {
+                let (base, end) = clamp(region);
+                frames.set(inner_frames(base, end), false);
+                found = true;
+            });
+            if let Err(e) = res {
+                log::warn!("failed to read the memory nodes of the device tree: {}", e);
+            }
+            found
+        }
+        Err(e) => {
+            log::warn!("{} at {:#x}", e, dtb);
+            false
+        }
+    };
+    if !found {
+        let (base, end) = match mailbox::arm_memory() {
+            Ok(region) => clamp(region),
+            Err(e) => {
+                log::warn!("{}, assuming the default memory split", e);
+                (0, DEFAULT_ARM_MEMORY_END)
+            }
+        };
+        frames.set(inner_frames(base, end), false);
+    }
+    // Only frames that were actually freed are counted, so overlapping regions count once.
+    frames.total = frames.free;
+
+    let kernel_end = &__heap_end as *const u8 as usize;
+    reserve_locked(&mut frames, 0, kernel_end);
+    if let Ok(fdt) = &fdt {
+        let (base, end) = clamp(fdt.region());
+        reserve_locked(&mut frames, base, end);
+        let res = fdt.for_each_reserved(|region| {
+            let (base, end) = clamp(region);
+            reserve_locked(&mut frames, base, end);
+        });
+        if let Err(e) = res {
+            log::warn!("failed to read the device tree memory reservations: {}", e);
+        }
+    }
+    frames.hint = kernel_end / PAGE_SIZE;
+
+    log::info!(
+        "{} MiB of memory, {} MiB free",
+        (frames.total * PAGE_SIZE) >> 20,
+        (frames.free * PAGE_SIZE) >> 20
+    );
+}
+
+fn reserve_locked(frames: &mut FrameAllocator, base: usize, end: usize) {
+    frames.set(outer_frames(base, end), true);
+}
+
+/// Marks the frames overlapping `base..base + size` as used, for memory owned by devices or the
+/// firmware.
+pub fn reserve(base: usize, size: usize) {
+    irq::without_interrupts(|| reserve_locked(&mut FRAMES.lock(), base, base + size))
+}
+
+/// Allocates a frame, returning its physical address.
+pub fn alloc_frame() -> Option<usize> {
+    alloc_frames(1, 1)
+}
+
+/// Allocates `count` contiguous frames, starting at a multiple of `align` frames, which must be a
+/// power of two. Returns the physical address of the first one.
+pub fn alloc_frames(count: usize, align: usize) -> Option<usize> {
+    assert!(
+        align.is_power_of_two(),
+        "frame alignment must be a power of two"
+    );
+    if count == 0 {
+        return None;
+    }
+    irq::without_interrupts(|| {
+        let mut frames = FRAMES.lock();
+        let start = frames.find(count, align)?;
+        frames.set(start..start + count, true);
+        frames.hint = start + count;
+        Some(start * PAGE_SIZE)
+    })
+}
+
+/// Frees the frame at `addr`.
+pub fn free_frame(addr: usize) {
+    free_frames(addr, 1)
+}
+
+/// Frees `count` frames starting at `addr`, which must have been allocated together.
+///
+/// # Panics
+///
+/// Panics if `addr` is not aligned to a frame or any of the frames is already free.
+pub fn free_frames(addr: usize, count: usize) {
+    assert!(addr % PAGE_SIZE == 0, "freeing unaligned frame {:#x}", addr);
+    let start = addr / PAGE_SIZE;
+    irq::without_interrupts(|| {
+        let mut frames = FRAMES.lock();
+        for frame in start..start + count {
+            assert!(
+                frame < FRAME_COUNT && frames.is_used(frame),
+                "freeing frame {:#x} which is not allocated",
+                frame * PAGE_SIZE
+            );
+        }
+        frames.set(start..start + count, false);
+    })
+}
+
+/// Gets the usage of the physical memory.
+pub fn stats() -> FrameStats {
+    irq::without_interrupts(|| {
+        let frames = FRAMES.lock();
+        FrameStats {
+            total: frames.total,
+            free: frames.free,
+        }
+    })
+}
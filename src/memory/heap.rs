@@ -0,0 +1,174 @@
+//! The kernel heap, behind the `#[global_allocator]`.
+//!
+//! The heap is the region between `__heap_start` and `__heap_end`, reserved by the linker script
+//! right after `.bss`. Free memory is kept in a linked list of holes sorted by address, and freed
+//! blocks are merged with their neighbours. Allocations are rounded to [`MIN_BLOCK_SIZE`] so that
+//! every hole can hold its own header.
+//!
+//! The heap is protected by a spin lock taken with IRQs masked, so interrupt handlers can allocate
+//! too.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem, ptr,
+};
+
+use super::align_up;
+use crate::irq;
+
+/// Size and alignment of the smallest block.
+const MIN_BLOCK_SIZE: usize = 2 * mem::size_of::<usize>();
+
+extern "C" {
+    static __heap_start: u8;
+    static __heap_end: u8;
+}
+
+/// A free region, whose header is stored at its start.
+struct Hole {
+    size: usize,
+    next: *mut Hole,
+}
+
+struct Heap {
+    /// First hole, the one with the lowest address.
+    first: *mut Hole,
+    size: usize,
+    used: usize,
+}
+
+// SAFETY: The holes are only accessed while holding the lock of the heap.
+unsafe impl Send for Heap {}
+
+/// Usage of the heap.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Size of the heap.
+    pub size: usize,
+    /// Bytes allocated, including rounding.
+    pub used: usize,
+}
+
+impl Heap {
+    /// Rounds a layout to the size and alignment of the block that holds it.
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let align = layout.align().max(MIN_BLOCK_SIZE);
+        let size = align_up(layout.size().max(MIN_BLOCK_SIZE), MIN_BLOCK_SIZE);
+        (size, align)
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::block_layout(layout);
+
+        let mut prev: *mut Hole = ptr::null_mut();
+        let mut cur = self.first;
+        while !cur.is_null() {
+            let hole_start = cur as usize;
+            let hole_end = hole_start + (*cur).size;
+            let next = (*cur).next;
+            // Both are multiples of `MIN_BLOCK_SIZE`, so the padding in front is either empty or
+            // big enough to stay as a hole.
+            let start = align_up(hole_start, align);
+            if start + size <= hole_end {
+                let mut link = next;
+                if start + size < hole_end {
+                    link = Self::write_hole(start + size, hole_end - start - size, link);
+                }
+                if start > hole_start {
+                    link = Self::write_hole(hole_start, start - hole_start, link);
+                }
+                if prev.is_null() {
+                    self.first = link;
+                } else {
+                    (*prev).next = link;
+                }
+                self.used += size;
+                return start as *mut u8;
+            }
+            prev = cur;
+            cur = next;
+        }
+        ptr::null_mut()
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::block_layout(layout);
+        let start = ptr as usize;
+        self.used -= size;
+
+        let mut prev: *mut Hole = ptr::null_mut();
+        let mut next = self.first;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let hole = Self::write_hole(start, size, next);
+        if !next.is_null() && start + size == next as usize {
+            (*hole).size += (*next).size;
+            (*hole).next = (*next).next;
+        }
+        if prev.is_null() {
+            self.first = hole;
+        } else if prev as usize + (*prev).size == start {
+            (*prev).size += (*hole).size;
+            (*prev).next = (*hole).next;
+        } else {
+            (*prev).next = hole;
+        }
+    }
+
+    unsafe fn write_hole(addr: usize, size: usize, next: *mut Hole) -> *mut Hole {
+        let hole = addr as *mut Hole;
+        hole.write(Hole { size, next });
+        hole
+    }
+}
+
+/// The kernel allocator.
+pub struct KernelAllocator {
+    heap: spin::Mutex<Heap>,
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator {
+    heap: spin::Mutex::new(Heap {
+        first: ptr::null_mut(),
+        size: 0,
+        used: 0,
+    }),
+};
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        irq::without_interrupts(|| self.heap.lock().alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        irq::without_interrupts(|| self.heap.lock().dealloc(ptr, layout))
+    }
+}
+
+/// Hands the heap region to the allocator.
+///
+/// # Safety
+///
+/// Must be called only once, after the MMU is enabled and before anything is allocated.
+pub unsafe fn init() {
+    let start = align_up(&__heap_start as *const u8 as usize, MIN_BLOCK_SIZE);
+    let end = &__heap_end as *const u8 as usize & !(MIN_BLOCK_SIZE - 1);
+    let mut heap = ALLOCATOR.heap.lock();
+    heap.first = Heap::write_hole(start, end - start, ptr::null_mut());
+    heap.size = end - start;
+}
+
+/// Gets the usage of the heap.
+pub fn stats() -> HeapStats {
+    irq::without_interrupts(|| {
+        let heap = ALLOCATOR.heap.lock();
+        HeapStats {
+            size: heap.size,
+            used: heap.used,
+        }
+    })
+}
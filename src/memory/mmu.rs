@@ -0,0 +1,539 @@
+//! MMU configuration and the kernel translation tables.
+//!
+//! The kernel uses a single identity mapped address space in `TTBR0_EL1` with a 4 KiB granule and
+//! a 32 bit (4 GiB) input address range, so translation starts at level 1. Memory is mapped with
+//! 2 MiB level 2 blocks where possible and with 4 KiB level 3 pages otherwise. The kernel image is
+//! always mapped with pages, so parts of it can be remapped without replacing the block that maps
+//! the code doing it.
+//!
+//! The initial map, built by [`init`], is:
+//!
+//! - `0x0..0x1000`: unmapped, to catch null pointer dereferences.
+//! - `0x1000..MMIO_BASE_ADDR`: normal write-back cacheable memory.
+//! - `MMIO_BASE_ADDR..MMIO_END_ADDR`: device memory (nGnRE).
+
+use core::fmt;
+
+use cortex_a::asm::barrier;
+use cortex_a::registers::{ID_AA64MMFR0_EL1, MAIR_EL1, SCTLR_EL1, TCR_EL1, TTBR0_EL1};
+use tock_registers::interfaces::{Readable, Writeable};
+
+use super::{align_down, align_up};
+use crate::drivers::MMIO_BASE_ADDR;
+use crate::error::{Error, ErrorKind};
+
+/// Size of a level 3 page.
+pub const PAGE_SIZE: usize = 4 * 1024;
+/// Size of a level 2 block.
+pub const BLOCK_SIZE: usize = 2 * 1024 * 1024;
+/// Size of the region mapped by a level 1 entry.
+const L1_ENTRY_SIZE: usize = 1024 * 1024 * 1024;
+/// Size of the virtual address space, as configured by `TCR_EL1.T0SZ`.
+pub const ADDRESS_SPACE_SIZE: usize = 1 << 32;
+/// End of the peripherals mapped as device memory.
+pub const MMIO_END_ADDR: usize = 0x4000_0000;
+
+/// Number of entries in a translation table.
+const ENTRIES: usize = 512;
+/// Number of tables available for level 2 and level 3 translation tables. Tables are never freed.
+const TABLE_POOL_SIZE: usize = 16;
+
+// Translation table descriptor bits.
+const DESC_VALID: u64 = 1 << 0;
+/// Set for table descriptors in levels 1 and 2 and for page descriptors in level 3. Clear for
+/// block descriptors.
+const DESC_TABLE_OR_PAGE: u64 = 1 << 1;
+const DESC_ATTR_INDEX_SHIFT: u64 = 2;
+const DESC_ATTR_INDEX_MASK: u64 = 0b111 << DESC_ATTR_INDEX_SHIFT;
+const DESC_AP_READ_ONLY: u64 = 1 << 7;
+const DESC_SH_OUTER: u64 = 0b10 << 8;
+const DESC_SH_INNER: u64 = 0b11 << 8;
+const DESC_ACCESS_FLAG: u64 = 1 << 10;
+const DESC_PXN: u64 = 1 << 53;
+const DESC_UXN: u64 = 1 << 54;
+const DESC_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+// `MAIR_EL1` attribute indices and encodings.
+const ATTR_DEVICE: u64 = 0;
+const ATTR_NORMAL: u64 = 1;
+const ATTR_NORMAL_UNCACHED: u64 = 2;
+const MAIR_DEVICE_NGNRE: u64 = 0x04;
+const MAIR_NORMAL_WRITE_BACK: u64 = 0xff;
+const MAIR_NORMAL_UNCACHED: u64 = 0x44;
+
+// `TCR_EL1` configuration: 4 KiB granule for `TTBR0_EL1`, inner shareable write-back table walks,
+// and walks through `TTBR1_EL1` disabled. `IPS` is filled in from `ID_AA64MMFR0_EL1`.
+const TCR_T0SZ: u64 = 64 - 32;
+const TCR_IRGN0_WRITE_BACK: u64 = 0b01 << 8;
+const TCR_ORGN0_WRITE_BACK: u64 = 0b01 << 10;
+const TCR_SH0_INNER: u64 = 0b11 << 12;
+const TCR_TG0_4K: u64 = 0b00 << 14;
+const TCR_EPD1: u64 = 1 << 23;
+const TCR_IPS_SHIFT: u64 = 32;
+
+// `SCTLR_EL1` bits.
+const SCTLR_M: u64 = 1 << 0;
+const SCTLR_C: u64 = 1 << 2;
+const SCTLR_I: u64 = 1 << 12;
+
+/// Memory type of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Normal write-back cacheable memory.
+    Normal,
+    /// Normal non-cacheable memory, for buffers shared with devices.
+    NormalUncached,
+    /// Device memory (nGnRE), for memory mapped registers.
+    Device,
+}
+
+/// Access permissions of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Readable and writeable.
+    ReadWrite,
+    /// Only readable.
+    ReadOnly,
+}
+
+/// Attributes of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    /// The memory type.
+    pub memory: MemoryType,
+    /// The access permissions.
+    pub access: Access,
+    /// Whether instructions may be fetched from the mapping.
+    pub executable: bool,
+}
+
+impl Attributes {
+    /// Attributes of kernel memory: normal cacheable, readable, writeable and executable.
+    pub const NORMAL: Self = Attributes {
+        memory: MemoryType::Normal,
+        access: Access::ReadWrite,
+        executable: true,
+    };
+
+    /// Attributes of memory mapped registers.
+    pub const DEVICE: Self = Attributes {
+        memory: MemoryType::Device,
+        access: Access::ReadWrite,
+        executable: false,
+    };
+
+    /// Attribute bits of a block or page descriptor. The descriptor type bits are not included.
+    fn to_descriptor(self) -> u64 {
+        let mut desc = DESC_VALID | DESC_ACCESS_FLAG;
+        desc |= match self.memory {
+            MemoryType::Normal => (ATTR_NORMAL << DESC_ATTR_INDEX_SHIFT) | DESC_SH_INNER,
+            MemoryType::NormalUncached => {
+                (ATTR_NORMAL_UNCACHED << DESC_ATTR_INDEX_SHIFT) | DESC_SH_OUTER
+            }
+            MemoryType::Device => (ATTR_DEVICE << DESC_ATTR_INDEX_SHIFT) | DESC_SH_OUTER,
+        };
+        if self.access == Access::ReadOnly {
+            desc |= DESC_AP_READ_ONLY;
+        }
+        // EL0 never executes from kernel mappings.
+        desc |= DESC_UXN;
+        if !self.executable {
+            desc |= DESC_PXN;
+        }
+        desc
+    }
+
+    fn from_descriptor(desc: u64) -> Self {
+        let memory = match (desc & DESC_ATTR_INDEX_MASK) >> DESC_ATTR_INDEX_SHIFT {
+            ATTR_NORMAL => MemoryType::Normal,
+            ATTR_NORMAL_UNCACHED => MemoryType::NormalUncached,
+            _ => MemoryType::Device,
+        };
+        let access = if desc & DESC_AP_READ_ONLY != 0 {
+            Access::ReadOnly
+        } else {
+            Access::ReadWrite
+        };
+        Attributes {
+            memory,
+            access,
+            executable: desc & DESC_PXN == 0,
+        }
+    }
+}
+
+/// The result of translating a virtual address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    /// The physical address the virtual address maps to.
+    pub phys: usize,
+    /// Size of the block or page that contains the address.
+    pub size: usize,
+    /// Attributes of the mapping.
+    pub attributes: Attributes,
+}
+
+/// Errors that can happen when changing mappings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// An address or size is not aligned to [`PAGE_SIZE`].
+    Unaligned,
+    /// The range is not inside the virtual address space.
+    OutOfRange,
+    /// There are no more translation tables available.
+    OutOfTables,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            MapError::Unaligned => "address or size is not page aligned",
+            MapError::OutOfRange => "range is outside the virtual address space",
+            MapError::OutOfTables => "out of translation tables",
+        })
+    }
+}
+
+impl Error for MapError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            MapError::Unaligned | MapError::OutOfRange => ErrorKind::InvalidArgument,
+            MapError::OutOfTables => ErrorKind::OutOfMemory,
+        }
+    }
+}
+
+#[repr(C, align(4096))]
+struct Table([u64; ENTRIES]);
+
+struct PageTables {
+    l1: Table,
+    pool: [Table; TABLE_POOL_SIZE],
+    pool_used: usize,
+}
+
+/// The kernel translation tables. Only accessed while holding `LOCK`, except by [`init`].
+static mut TABLES: PageTables = PageTables {
+    l1: Table([0; ENTRIES]),
+    pool: [const { Table([0; ENTRIES]) }; TABLE_POOL_SIZE],
+    pool_used: 0,
+};
+
+/// Serializes changes to the translation tables.
+static LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+extern "C" {
+    static __bss_end: u8;
+}
+
+impl PageTables {
+    fn alloc_table(&mut self) -> Result<&'static mut Table, MapError> {
+        let table = self
+            .pool
+            .get_mut(self.pool_used)
+            .ok_or(MapError::OutOfTables)?;
+        self.pool_used += 1;
+        // SAFETY: Tables are never freed and the pool lives in a static, so each table is only
+        // handed out once and lives forever.
+        Ok(unsafe { &mut *(table as *mut Table) })
+    }
+
+    /// Gets the level 2 table for `virt`, allocating it if `create` is set.
+    fn l2_table(
+        &mut self,
+        virt: usize,
+        create: bool,
+    ) -> Result<Option<&'static mut Table>, MapError> {
+        let idx = virt / L1_ENTRY_SIZE;
+        let desc = self.l1.0[idx];
+        if desc & DESC_VALID != 0 {
+            return Ok(Some(table_at(desc)));
+        }
+        if !create {
+            return Ok(None);
+        }
+        let table = self.alloc_table()?;
+        self.l1.0[idx] = table as *mut Table as u64 | DESC_TABLE_OR_PAGE | DESC_VALID;
+        Ok(Some(table))
+    }
+
+    /// Gets the level 3 table under the level 2 entry `entry`, which maps the block at `virt`. An
+    /// empty table is created if the entry is invalid and a block is split into pages with the
+    /// same attributes if it maps a block.
+    fn l3_table(&mut self, entry: &mut u64, virt: usize) -> Result<&'static mut Table, MapError> {
+        let desc = *entry;
+        if desc & DESC_VALID != 0 && desc & DESC_TABLE_OR_PAGE != 0 {
+            return Ok(table_at(desc));
+        }
+
+        let table = self.alloc_table()?;
+        if desc & DESC_VALID != 0 {
+            let block_phys = desc & DESC_ADDR_MASK;
+            let attrs = desc & !DESC_ADDR_MASK;
+            for (i, page) in table.0.iter_mut().enumerate() {
+                *page = (block_phys + (i * PAGE_SIZE) as u64) | attrs | DESC_TABLE_OR_PAGE;
+            }
+        }
+        let new = table as *mut Table as u64 | DESC_TABLE_OR_PAGE | DESC_VALID;
+        write_entry(entry, new, align_down(virt, BLOCK_SIZE));
+        Ok(table)
+    }
+
+    /// Maps `size` bytes at `virt` to `phys` when `phys` is `Some` or unmaps them otherwise. Blocks
+    /// are only used if `allow_blocks` is set.
+    fn update(
+        &mut self,
+        virt: usize,
+        phys: Option<usize>,
+        size: usize,
+        attrs: Attributes,
+        allow_blocks: bool,
+    ) -> Result<(), MapError> {
+        if virt % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 || phys.unwrap_or(0) % PAGE_SIZE != 0 {
+            return Err(MapError::Unaligned);
+        }
+        if virt
+            .checked_add(size)
+            .map_or(true, |end| end > ADDRESS_SPACE_SIZE)
+        {
+            return Err(MapError::OutOfRange);
+        }
+
+        let mut offset = 0;
+        while offset < size {
+            let va = virt + offset;
+            let pa = phys.map(|phys| phys + offset);
+
+            let l2 = match self.l2_table(va, pa.is_some())? {
+                Some(l2) => l2,
+                None => {
+                    // Nothing is mapped in this level 1 entry, so there is nothing to unmap.
+                    offset += align_up(va + 1, L1_ENTRY_SIZE) - va;
+                    continue;
+                }
+            };
+            let entry = &mut l2.0[(va / BLOCK_SIZE) % ENTRIES];
+
+            let is_table = *entry & DESC_VALID != 0 && *entry & DESC_TABLE_OR_PAGE != 0;
+            let block_aligned = va % BLOCK_SIZE == 0 && pa.unwrap_or(0) % BLOCK_SIZE == 0;
+            if allow_blocks && !is_table && block_aligned && size - offset >= BLOCK_SIZE {
+                let new = pa.map_or(0, |pa| pa as u64 | attrs.to_descriptor());
+                write_entry(entry, new, va);
+                offset += BLOCK_SIZE;
+                continue;
+            }
+            if pa.is_none() && *entry & DESC_VALID == 0 {
+                offset += align_up(va + 1, BLOCK_SIZE) - va;
+                continue;
+            }
+
+            let l3 = self.l3_table(entry, va)?;
+            let new = pa.map_or(0, |pa| {
+                pa as u64 | attrs.to_descriptor() | DESC_TABLE_OR_PAGE
+            });
+            write_entry(&mut l3.0[(va / PAGE_SIZE) % ENTRIES], new, va);
+            offset += PAGE_SIZE;
+        }
+
+        Ok(())
+    }
+
+    fn translate(&self, virt: usize) -> Option<Translation> {
+        if virt >= ADDRESS_SPACE_SIZE {
+            return None;
+        }
+
+        let l1 = self.l1.0[virt / L1_ENTRY_SIZE];
+        if l1 & DESC_VALID == 0 {
+            return None;
+        }
+        let l2 = table_at(l1).0[(virt / BLOCK_SIZE) % ENTRIES];
+        if l2 & DESC_VALID == 0 {
+            return None;
+        }
+        let (desc, size) = if l2 & DESC_TABLE_OR_PAGE == 0 {
+            (l2, BLOCK_SIZE)
+        } else {
+            (table_at(l2).0[(virt / PAGE_SIZE) % ENTRIES], PAGE_SIZE)
+        };
+        if desc & DESC_VALID == 0 {
+            return None;
+        }
+
+        Some(Translation {
+            phys: (desc & DESC_ADDR_MASK) as usize + virt % size,
+            size,
+            attributes: Attributes::from_descriptor(desc),
+        })
+    }
+}
+
+fn table_at(desc: u64) -> &'static mut Table {
+    // SAFETY: Table descriptors are only ever created pointing to tables in `TABLES`, which are
+    // identity mapped.
+    unsafe { &mut *((desc & DESC_ADDR_MASK) as *mut Table) }
+}
+
+/// Writes a translation table entry that translates `virt`. If a valid entry is replaced by a
+/// different valid entry the break-before-make sequence is used.
+fn write_entry(entry: &mut u64, new: u64, virt: usize) {
+    let old = *entry;
+    if old & DESC_VALID != 0 && new & DESC_VALID != 0 && old != new {
+        *entry = 0;
+        invalidate_tlb(virt);
+    }
+    *entry = new;
+    if old & DESC_VALID != 0 {
+        invalidate_tlb(virt);
+    } else {
+        // Make the new entry visible to the table walker.
+        barrier::dsb(barrier::ISHST);
+    }
+}
+
+/// Invalidates the TLB entries for `virt` on all cores.
+fn invalidate_tlb(virt: usize) {
+    unsafe {
+        core::arch::asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {page}",
+            "dsb ish",
+            "isb",
+            page = in(reg) virt >> 12,
+        );
+    }
+}
+
+/// Builds the initial kernel map described in the module documentation.
+///
+/// # Safety
+///
+/// Must be called only once, by the boot core, before any other core is started and before the MMU
+/// is enabled. The translation tables are accessed without taking the lock because exclusive
+/// accesses may not work while the MMU is off.
+pub unsafe fn init() -> Result<(), MapError> {
+    let tables = &mut TABLES;
+    let kernel_end = align_up(&__bss_end as *const u8 as usize, BLOCK_SIZE);
+
+    tables.update(
+        PAGE_SIZE,
+        Some(PAGE_SIZE),
+        kernel_end - PAGE_SIZE,
+        Attributes::NORMAL,
+        false,
+    )?;
+    tables.update(
+        kernel_end,
+        Some(kernel_end),
+        MMIO_BASE_ADDR - kernel_end,
+        Attributes::NORMAL,
+        true,
+    )?;
+    tables.update(
+        MMIO_BASE_ADDR,
+        Some(MMIO_BASE_ADDR),
+        MMIO_END_ADDR - MMIO_BASE_ADDR,
+        Attributes::DEVICE,
+        true,
+    )
+}
+
+/// Values of the MMU configuration registers shared by all cores.
+#[derive(Debug, Clone, Copy)]
+pub struct CoreConfig {
+    /// Value of `MAIR_EL1`.
+    pub mair: u64,
+    /// Value of `TCR_EL1`.
+    pub tcr: u64,
+    /// Value of `TTBR0_EL1`.
+    pub ttbr0: u64,
+}
+
+/// Gets the MMU configuration that every core uses. Secondary cores enable their MMU from assembly
+/// with these values, see [`crate::smp`].
+pub fn core_config() -> CoreConfig {
+    let pa_range = ID_AA64MMFR0_EL1.get() & 0xf;
+    CoreConfig {
+        mair: (MAIR_DEVICE_NGNRE << (8 * ATTR_DEVICE))
+            | (MAIR_NORMAL_WRITE_BACK << (8 * ATTR_NORMAL))
+            | (MAIR_NORMAL_UNCACHED << (8 * ATTR_NORMAL_UNCACHED)),
+        tcr: TCR_T0SZ
+            | TCR_IRGN0_WRITE_BACK
+            | TCR_ORGN0_WRITE_BACK
+            | TCR_SH0_INNER
+            | TCR_TG0_4K
+            | TCR_EPD1
+            | (pa_range.min(0b101) << TCR_IPS_SHIFT),
+        // SAFETY: Only the address is taken.
+        ttbr0: unsafe { &TABLES.l1 as *const Table as u64 },
+    }
+}
+
+/// Enables the MMU and the data and instruction caches on the current core, using the kernel
+/// translation tables. Used by the boot core, secondary cores do the same in `boot.S`.
+///
+/// # Safety
+///
+/// [`init`] must have been called before.
+pub unsafe fn enable() {
+    let config = core_config();
+    MAIR_EL1.set(config.mair);
+    TCR_EL1.set(config.tcr);
+    TTBR0_EL1.set(config.ttbr0);
+
+    core::arch::asm!("dsb ish", "tlbi vmalle1", "dsb nsh", "isb");
+
+    SCTLR_EL1.set(SCTLR_EL1.get() | SCTLR_M | SCTLR_C | SCTLR_I);
+    barrier::isb(barrier::SY);
+}
+
+/// Checks whether the MMU is enabled on the current core.
+pub fn is_enabled() -> bool {
+    SCTLR_EL1.get() & SCTLR_M != 0
+}
+
+/// Maps `size` bytes at the virtual address `virt` to the physical address `phys`, replacing any
+/// existing mapping. All of them must be multiples of [`PAGE_SIZE`]. Blocks are used whenever the
+/// addresses and size allow it.
+pub fn map(virt: usize, phys: usize, size: usize, attrs: Attributes) -> Result<(), MapError> {
+    let _guard = LOCK.lock();
+    // SAFETY: We are holding the lock.
+    unsafe { TABLES.update(virt, Some(phys), size, attrs, true) }
+}
+
+/// Removes the mapping of `size` bytes at the virtual address `virt`. Both must be multiples of
+/// [`PAGE_SIZE`].
+pub fn unmap(virt: usize, size: usize) -> Result<(), MapError> {
+    let _guard = LOCK.lock();
+    // SAFETY: We are holding the lock.
+    unsafe { TABLES.update(virt, None, size, Attributes::NORMAL, true) }
+}
+
+/// Checks whether the current core can read `virt` at EL1, by asking the MMU to translate it with
+/// `at s1e1r`. Unlike [`translate`], it doesn't take the lock of the tables, so it can be used from
+/// the panic and exception handlers.
+pub fn is_readable(virt: usize) -> bool {
+    let par = crate::irq::without_interrupts(|| {
+        let par: u64;
+        // SAFETY: Only translates the address and reads the result, which clobbers `PAR_EL1`.
+        unsafe {
+            core::arch::asm!(
+                "at s1e1r, {virt}",
+                "isb",
+                "mrs {par}, PAR_EL1",
+                virt = in(reg) virt,
+                par = out(reg) par,
+            )
+        };
+        par
+    });
+    // `PAR_EL1.F` is set when the translation faulted.
+    par & 1 == 0
+}
+
+/// Translates a virtual address by walking the kernel translation tables.
+pub fn translate(virt: usize) -> Option<Translation> {
+    let _guard = LOCK.lock();
+    // SAFETY: We are holding the lock.
+    unsafe { TABLES.translate(virt) }
+}
@@ -0,0 +1,136 @@
+//! Stopping the kernel when it panics.
+//!
+//! The first core to panic stops the others before reporting, so that they can't keep printing or
+//! changing the state being reported. Cores sleeping in [`crate::boot::child_loop`] see
+//! [`is_panicking`] when they wake up, and cores running something are interrupted through their
+//! mailbox [`HALT_MAILBOX`]. A core spinning with its interrupts masked can't be stopped, which is
+//! reported after waiting for [`HALT_ACK_TIMEOUT`].
+//!
+//! The locks of the consoles are then forced open, since a stopped core, or the panicking core
+//! itself, may have been holding them. Finally, the board is reset by the watchdog if a delay was
+//! set with [`set_reboot_delay`].
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::drivers::{local_intc, power};
+use crate::irq::{self, IrqError, IrqSource, LocalIrq};
+use crate::smp::{self, CoreState};
+use crate::time::{Duration, Instant};
+use crate::utils::get_cpu;
+
+/// Mailbox used to interrupt the cores being stopped.
+const HALT_MAILBOX: usize = 3;
+/// How long to wait for the other cores to stop.
+const HALT_ACK_TIMEOUT: Duration = Duration::from_millis(10);
+
+const NO_CORE: usize = usize::MAX;
+const NO_REBOOT: u64 = u64::MAX;
+
+/// The core that panicked first, or [`NO_CORE`].
+static PANICKING_CORE: AtomicUsize = AtomicUsize::new(NO_CORE);
+/// One bit per core that stopped.
+static HALTED: AtomicUsize = AtomicUsize::new(0);
+/// Delay before rebooting after a panic in milliseconds, or [`NO_REBOOT`].
+static REBOOT_DELAY_MS: AtomicU64 = AtomicU64::new(NO_REBOOT);
+
+/// What the panic handler should do, see [`enter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    /// This is the first panic. `unstopped` has a bit set for each core that didn't stop.
+    Report { unstopped: usize },
+    /// The panic handler itself panicked, so the report is abandoned.
+    Nested,
+}
+
+/// Registers the handler of the halt interrupt. Must be called once, after [`irq::init`].
+pub fn init() -> Result<(), IrqError> {
+    irq::register(IrqSource::Local(LocalIrq::Mailbox3), handle_halt_irq)
+}
+
+/// Enables the halt interrupt on the current core. Must be called on every core.
+pub fn init_core() {
+    irq::enable(IrqSource::Local(LocalIrq::Mailbox3));
+}
+
+/// Whether a core panicked.
+pub fn is_panicking() -> bool {
+    PANICKING_CORE.load(Ordering::Acquire) != NO_CORE
+}
+
+/// Sets the delay before the board is reset after a panic, or `None` to stop forever.
+pub fn set_reboot_delay(delay: Option<Duration>) {
+    let ms = delay.map_or(NO_REBOOT, |delay| delay.as_millis() as u64);
+    REBOOT_DELAY_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Gets the delay set by [`set_reboot_delay`].
+pub fn reboot_delay() -> Option<Duration> {
+    match REBOOT_DELAY_MS.load(Ordering::Relaxed) {
+        NO_REBOOT => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+/// Called first by the panic handler. The first core to panic stops the others and unlocks the
+/// consoles. Any other core panicking at the same time is stopped right away.
+pub fn enter() -> Entry {
+    irq::local_disable();
+    let cpu = get_cpu() as usize;
+    match PANICKING_CORE.compare_exchange(NO_CORE, cpu, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {
+            let unstopped = stop_other_cores(cpu);
+            // SAFETY: The other cores are stopped, the ones that aren't are reported.
+            unsafe { crate::console::force_unlock() };
+            Entry::Report { unstopped }
+        }
+        Err(core) if core == cpu => Entry::Nested,
+        Err(_) => halt_core(),
+    }
+}
+
+/// Called last by the panic handler. Resets the board if a reboot delay is set, and stops the
+/// current core.
+pub fn finish() -> ! {
+    if let Some(delay) = reboot_delay() {
+        power::reset_after(delay);
+    }
+    halt_core()
+}
+
+/// Stops the current core for good.
+pub fn halt_core() -> ! {
+    // SAFETY: Only masks every exception.
+    unsafe { core::arch::asm!("msr daifset, #0xf") };
+    HALTED.fetch_or(1 << get_cpu(), Ordering::AcqRel);
+    loop {
+        cortex_a::asm::wfe();
+    }
+}
+
+/// Interrupts every running core other than `cpu` and waits for them to stop. Returns a bit for
+/// each core that didn't stop in time.
+fn stop_other_cores(cpu: usize) -> usize {
+    let mut running = 0;
+    for core in (0..smp::NUM_CORES).filter(|&core| core != cpu) {
+        if smp::core_state(core) != CoreState::Parked {
+            local_intc::send_mailbox(core, HALT_MAILBOX, 1);
+            running |= 1 << core;
+        }
+    }
+    cortex_a::asm::sev();
+
+    let start = Instant::now();
+    while HALTED.load(Ordering::Acquire) & running != running && start.elapsed() < HALT_ACK_TIMEOUT
+    {
+        core::hint::spin_loop();
+    }
+    running & !HALTED.load(Ordering::Acquire)
+}
+
+fn handle_halt_irq() {
+    let cpu = get_cpu() as usize;
+    local_intc::clear_mailbox(cpu, HALT_MAILBOX, u32::MAX);
+    if is_panicking() {
+        halt_core();
+    }
+}
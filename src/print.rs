@@ -1,11 +1,10 @@
-use crate::drivers;
-
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    drivers::mini_uart::_mu_print(args);
+    crate::console::_print(args);
 }
 
-/// Print macro, syntax is like [`std::print`]. Current implementation prints to the Mini UART.
+/// Print macro, syntax is like [`std::print`]. Prints to every registered console, see
+/// [`crate::console`].
 #[macro_export]
 macro_rules! print {
     ($($tok:tt)*) => ({
@@ -13,11 +12,12 @@ macro_rules! print {
     });
 }
 
-/// Println macro, syntax is like [`std::println`]. Current implementation prints to the Mini UART.
+/// Println macro, syntax is like [`std::println`]. Prints to every registered console, see
+/// [`crate::console`].
 #[macro_export]
 macro_rules! println {
     () => ({
-        $crate::print::_print("\n");
+        $crate::print::_print(format_args!("\n"));
     });
 
     ($($tok:tt)*) => ({
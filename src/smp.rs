@@ -0,0 +1,230 @@
+//! Secondary core bring-up.
+//!
+//! Every core other than the boot core waits in `_child_spin` (see `boot/boot.S`) until
+//! [`start_core`] publishes a [`BootInfo`] for it. The core reads it with its caches still off, so
+//! the boot info is cleaned to the point of coherency before the core is woken up. The core then
+//! enables its MMU with the configuration found in the boot info, before touching any memory
+//! through the caches, switches to its own stack and calls [`_smp_core_entry`], which acknowledges
+//! the start and jumps to the requested entry point.
+
+mod queue;
+pub mod work;
+
+pub use work::{spawn_on, JoinHandle};
+
+use core::{
+    fmt, mem,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::boot::BOOT_CORE_ID;
+use crate::error::{Error, ErrorKind};
+use crate::memory::{self, mmu};
+use crate::time::{Duration, Instant};
+
+/// Number of cores in the BCM2837.
+pub const NUM_CORES: usize = 4;
+/// Size of the stack of each secondary core.
+pub const CORE_STACK_SIZE: usize = 64 * 1024;
+
+/// How long [`start_core`] waits for the core to acknowledge the start before giving up.
+const START_ACK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// State of a core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CoreState {
+    /// Waiting in `_child_spin` to be started.
+    Parked = 0,
+    /// Released by [`start_core`], but didn't reach Rust code yet.
+    Starting = 1,
+    /// Running its entry point.
+    Running = 2,
+}
+
+impl CoreState {
+    fn from_u8(val: u8) -> Self {
+        match val {
+            0 => CoreState::Parked,
+            1 => CoreState::Starting,
+            _ => CoreState::Running,
+        }
+    }
+}
+
+/// Errors that can happen when starting a core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    /// The core doesn't exist or is the boot core.
+    InvalidCore,
+    /// The core was already started.
+    AlreadyStarted,
+    /// The core didn't acknowledge the start in time.
+    NotAcknowledged,
+    /// The core was never started, or failed to start.
+    NotRunning,
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SmpError::InvalidCore => "invalid core",
+            SmpError::AlreadyStarted => "core was already started",
+            SmpError::NotAcknowledged => "core did not acknowledge the start",
+            SmpError::NotRunning => "core is not running",
+        })
+    }
+}
+
+impl Error for SmpError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            SmpError::InvalidCore => ErrorKind::InvalidArgument,
+            SmpError::AlreadyStarted => ErrorKind::AlreadyExists,
+            SmpError::NotAcknowledged => ErrorKind::Timeout,
+            SmpError::NotRunning => ErrorKind::InvalidArgument,
+        }
+    }
+}
+
+/// Information read by `_child_spin` when a core is started. The layout must match the offsets
+/// used in `boot.S`, and it must fit in a cache line.
+#[repr(C, align(64))]
+struct BootInfo {
+    /// Top of the stack of the core. A core is released once this is not zero.
+    stack_top: usize,
+    /// Value of `MAIR_EL1`.
+    mair: u64,
+    /// Value of `TCR_EL1`.
+    tcr: u64,
+    /// Value of `TTBR0_EL1`.
+    ttbr0: u64,
+    /// The entry point, a `fn(usize) -> !`.
+    entry: usize,
+    /// The argument of the entry point.
+    arg: usize,
+}
+
+/// Kept in `.data` rather than `.bss`, since the secondary cores read it before the boot core
+/// clears `.bss`, and could otherwise be released by whatever was left in memory.
+#[no_mangle]
+#[link_section = ".data"]
+static mut SMP_BOOT_INFO: [BootInfo; NUM_CORES] = [const {
+    BootInfo {
+        stack_top: 0,
+        mair: 0,
+        tcr: 0,
+        ttbr0: 0,
+        entry: 0,
+        arg: 0,
+    }
+}; NUM_CORES];
+
+static CORE_STATES: [AtomicU8; NUM_CORES] =
+    [const { AtomicU8::new(CoreState::Parked as u8) }; NUM_CORES];
+
+/// Stack of a secondary core. Stacks grow down, so the guard page at the start is hit on overflow
+/// instead of the stack of another core.
+#[repr(C, align(4096))]
+struct CoreStack {
+    guard: [u8; mmu::PAGE_SIZE],
+    stack: [u8; CORE_STACK_SIZE],
+}
+
+/// Stacks of the secondary cores. The boot core uses the boot stack below the kernel image.
+static mut CORE_STACKS: [CoreStack; NUM_CORES - 1] = [const {
+    CoreStack {
+        guard: [0; mmu::PAGE_SIZE],
+        stack: [0; CORE_STACK_SIZE],
+    }
+}; NUM_CORES - 1];
+
+/// Gets the id of the boot core.
+#[inline(always)]
+pub fn boot_core() -> usize {
+    BOOT_CORE_ID as usize
+}
+
+/// Gets the state of a core.
+pub fn core_state(cpu: usize) -> CoreState {
+    CoreState::from_u8(CORE_STATES[cpu].load(Ordering::Acquire))
+}
+
+/// Marks the boot core as running and unmaps the guard pages of the secondary core stacks. Must be
+/// called by the boot core after the MMU is enabled.
+pub fn init() -> Result<(), mmu::MapError> {
+    CORE_STATES[boot_core()].store(CoreState::Running as u8, Ordering::Release);
+    // SAFETY: Only the addresses of the guard pages are taken.
+    unsafe {
+        for stack in CORE_STACKS.iter() {
+            mmu::unmap(stack.guard.as_ptr() as usize, mmu::PAGE_SIZE)?;
+        }
+    }
+    Ok(())
+}
+
+/// Gets the address right after the end of the stack of `cpu`.
+fn stack_top(cpu: usize) -> usize {
+    let idx = if cpu > boot_core() { cpu - 1 } else { cpu };
+    // SAFETY: Only the address of the stack is taken.
+    unsafe { CORE_STACKS[idx].stack.as_ptr_range().end as usize }
+}
+
+/// Starts `cpu`, which will call `entry(arg)` on its own stack. Blocks until the core acknowledges
+/// that it started.
+pub fn start_core(cpu: usize, entry: fn(usize) -> !, arg: usize) -> Result<(), SmpError> {
+    if cpu >= NUM_CORES || cpu == boot_core() {
+        return Err(SmpError::InvalidCore);
+    }
+    CORE_STATES[cpu]
+        .compare_exchange(
+            CoreState::Parked as u8,
+            CoreState::Starting as u8,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        )
+        .map_err(|_| SmpError::AlreadyStarted)?;
+
+    let config = mmu::core_config();
+    // SAFETY: The core is parked, so it is only reading its boot info, and no other core writes
+    // to it since we changed its state from parked.
+    unsafe {
+        let info = &mut SMP_BOOT_INFO[cpu];
+        info.mair = config.mair;
+        info.tcr = config.tcr;
+        info.ttbr0 = config.ttbr0;
+        info.entry = entry as usize;
+        info.arg = arg;
+        memory::clean_dcache_range(info as *const BootInfo as usize, mem::size_of::<BootInfo>());
+
+        // The stack is written last, once everything else is visible, since it releases the core.
+        info.stack_top = stack_top(cpu);
+        memory::clean_dcache_range(info as *const BootInfo as usize, mem::size_of::<BootInfo>());
+    }
+    cortex_a::asm::sev();
+
+    let start = Instant::now();
+    while start.elapsed() < START_ACK_TIMEOUT {
+        if core_state(cpu) == CoreState::Running {
+            log::debug!("core {} started", cpu);
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(SmpError::NotAcknowledged)
+}
+
+/// First Rust code executed by a secondary core, called from `_child_spin` with the MMU enabled.
+#[no_mangle]
+unsafe extern "C" fn _smp_core_entry(cpu: usize) -> ! {
+    crate::exception::init();
+    crate::time::init_core();
+    crate::panic::init_core();
+
+    let info = &SMP_BOOT_INFO[cpu];
+    let entry = mem::transmute::<usize, fn(usize) -> !>(info.entry);
+    let arg = info.arg;
+    CORE_STATES[cpu].store(CoreState::Running as u8, Ordering::Release);
+
+    entry(arg)
+}
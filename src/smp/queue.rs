@@ -0,0 +1,108 @@
+//! Lock-free bounded multi-producer single-consumer queue.
+//!
+//! This is Dmitry Vyukov's bounded queue: every slot has a sequence number that tells producers
+//! and the consumer whether the slot is free for the current lap around the buffer. Producers
+//! reserve a slot by advancing `tail` with a compare and swap, and the single consumer advances
+//! `head` with a plain store.
+
+use core::{
+    cell::UnsafeCell,
+    cmp::Ordering as CmpOrdering,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+struct Slot<T> {
+    /// Sequence number of the slot minus the index of the slot, so that every slot starts at zero
+    /// and the queue can be built in a constant.
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Bounded queue of `N` elements.
+pub struct Queue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// SAFETY: Values are only ever accessed by the producer that reserved the slot or by the consumer
+// after the producer released it.
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
+
+impl<T, const N: usize> Queue<T, N> {
+    /// Creates an empty queue.
+    pub const fn new() -> Self {
+        Queue {
+            slots: [const {
+                Slot {
+                    seq: AtomicUsize::new(0),
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                }
+            }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn seq(&self, pos: usize) -> usize {
+        self.slots[pos % N]
+            .seq
+            .load(Ordering::Acquire)
+            .wrapping_add(pos % N)
+    }
+
+    fn set_seq(&self, pos: usize, seq: usize) {
+        self.slots[pos % N]
+            .seq
+            .store(seq.wrapping_sub(pos % N), Ordering::Release);
+    }
+
+    /// Pushes a value to the back of the queue. Can be called from any core. Gives the value back
+    /// if the queue is full.
+    pub fn push(&self, val: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let diff = self.seq(pos).wrapping_sub(pos) as isize;
+            match diff.cmp(&0) {
+                CmpOrdering::Equal => {
+                    match self.tail.compare_exchange_weak(
+                        pos,
+                        pos.wrapping_add(1),
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => break,
+                        Err(cur) => pos = cur,
+                    }
+                }
+                CmpOrdering::Less => return Err(val),
+                CmpOrdering::Greater => pos = self.tail.load(Ordering::Relaxed),
+            }
+        }
+
+        // SAFETY: We reserved the slot, so nobody else accesses it until the sequence number is
+        // updated.
+        unsafe { (*self.slots[pos % N].value.get()).write(val) };
+        self.set_seq(pos, pos.wrapping_add(1));
+        Ok(())
+    }
+
+    /// Pops the value at the front of the queue.
+    ///
+    /// # Safety
+    ///
+    /// There must be a single consumer: no two cores may call this function concurrently.
+    pub unsafe fn pop(&self) -> Option<T> {
+        let pos = self.head.load(Ordering::Relaxed);
+        if self.seq(pos) != pos.wrapping_add(1) {
+            return None;
+        }
+
+        let val = ptr::read((*self.slots[pos % N].value.get()).as_ptr());
+        self.set_seq(pos, pos.wrapping_add(N));
+        self.head.store(pos.wrapping_add(1), Ordering::Relaxed);
+        Some(val)
+    }
+}
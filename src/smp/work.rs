@@ -0,0 +1,238 @@
+//! Running closures on other cores.
+//!
+//! Every core running [`crate::boot::child_loop`] consumes jobs from its own [`Queue`], sleeping
+//! with `wfe` while it is empty. There is no heap, so jobs are kept in a static pool of packets,
+//! each with room for [`JOB_STORAGE_SIZE`] bytes. A packet first stores the closure and, once the
+//! closure has run, its result, until the [`JoinHandle`] collects it.
+
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem::{self, MaybeUninit},
+    ptr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use super::queue::Queue;
+use super::{CoreState, SmpError, NUM_CORES};
+
+/// Maximum size in bytes of both the closures sent to other cores and their results.
+pub const JOB_STORAGE_SIZE: usize = 128;
+/// Maximum alignment of both the closures sent to other cores and their results.
+pub const JOB_STORAGE_ALIGN: usize = 16;
+/// Number of jobs that can exist at the same time, either queued, running or waiting to be joined.
+const MAX_JOBS: usize = 32;
+
+// Packet states. `DETACHED` is combined with `QUEUED` or `RUNNING` when the handle is dropped.
+const FREE: u8 = 0;
+const CLAIMED: u8 = 1;
+const QUEUED: u8 = 2;
+const RUNNING: u8 = 3;
+const DONE: u8 = 4;
+const DETACHED: u8 = 0x80;
+
+#[repr(C, align(16))]
+struct Storage(MaybeUninit<[u8; JOB_STORAGE_SIZE]>);
+
+struct Packet {
+    state: AtomicU8,
+    /// Reads the closure from the storage, runs it and writes the result to the storage.
+    run: UnsafeCell<unsafe fn(*mut u8)>,
+    /// Drops the result in the storage.
+    drop_result: UnsafeCell<unsafe fn(*mut u8)>,
+    storage: UnsafeCell<Storage>,
+}
+
+// SAFETY: The fields other than `state` are only accessed by whoever `state` says owns the packet.
+unsafe impl Sync for Packet {}
+
+unsafe fn noop(_: *mut u8) {}
+
+static PACKETS: [Packet; MAX_JOBS] = [const {
+    Packet {
+        state: AtomicU8::new(FREE),
+        run: UnsafeCell::new(noop),
+        drop_result: UnsafeCell::new(noop),
+        storage: UnsafeCell::new(Storage(MaybeUninit::uninit())),
+    }
+}; MAX_JOBS];
+
+/// Queues of packet indices, one per core.
+static QUEUES: [Queue<u8, MAX_JOBS>; NUM_CORES] = [const { Queue::new() }; NUM_CORES];
+
+/// Compile time check that a job of type `F` returning `T` fits in a packet.
+struct AssertFits<F, T>(PhantomData<(F, T)>);
+
+impl<F, T> AssertFits<F, T> {
+    const OK: () = assert!(
+        mem::size_of::<F>() <= JOB_STORAGE_SIZE
+            && mem::size_of::<T>() <= JOB_STORAGE_SIZE
+            && mem::align_of::<F>() <= JOB_STORAGE_ALIGN
+            && mem::align_of::<T>() <= JOB_STORAGE_ALIGN,
+        "closure or result too big to be sent to another core"
+    );
+}
+
+unsafe fn run_job<F: FnOnce() -> T, T>(storage: *mut u8) {
+    let f = ptr::read(storage as *mut F);
+    ptr::write(storage as *mut T, f());
+}
+
+unsafe fn drop_result<T>(storage: *mut u8) {
+    ptr::drop_in_place(storage as *mut T);
+}
+
+/// Claims a free packet, waiting for one to be freed if all of them are in use.
+fn claim_packet() -> usize {
+    loop {
+        for (i, packet) in PACKETS.iter().enumerate() {
+            if packet
+                .state
+                .compare_exchange(FREE, CLAIMED, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return i;
+            }
+        }
+        // Packets are freed by `JoinHandle` and by workers, both of which send an event.
+        cortex_a::asm::wfe();
+    }
+}
+
+fn free_packet(packet: &Packet) {
+    packet.state.store(FREE, Ordering::Release);
+    cortex_a::asm::sev();
+}
+
+/// Sends `f` to be run on `cpu`. The returned handle can be used to wait for the result.
+///
+/// The job is run by [`crate::boot::child_loop`], so `cpu` must be a secondary core that was
+/// started with it, otherwise the job would never run and joining it would wait forever. Blocks
+/// while the maximum number of jobs are in flight. The size of `f` and of its result is limited to
+/// [`JOB_STORAGE_SIZE`], which is checked at compile time.
+pub fn spawn_on<F, T>(cpu: usize, f: F) -> Result<JoinHandle<T>, SmpError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    #[allow(clippy::let_unit_value)]
+    let _ = AssertFits::<F, T>::OK;
+    if cpu >= NUM_CORES || cpu == super::boot_core() {
+        return Err(SmpError::InvalidCore);
+    }
+    if super::core_state(cpu) != CoreState::Running {
+        return Err(SmpError::NotRunning);
+    }
+
+    let idx = claim_packet();
+    let packet = &PACKETS[idx];
+    // SAFETY: We claimed the packet, so we are the only ones accessing it, and it fits `F`.
+    unsafe {
+        *packet.run.get() = run_job::<F, T>;
+        *packet.drop_result.get() = drop_result::<T>;
+        ptr::write((*packet.storage.get()).0.as_mut_ptr() as *mut F, f);
+    }
+    packet.state.store(QUEUED, Ordering::Release);
+
+    // There are as many queue slots as packets, so this can't fail.
+    if QUEUES[cpu].push(idx as u8).is_err() {
+        unreachable!("work queue of core {} overflowed", cpu);
+    }
+    cortex_a::asm::sev();
+
+    Ok(JoinHandle {
+        packet,
+        _marker: PhantomData,
+    })
+}
+
+/// Runs the next job queued for `cpu`, returning whether there was one.
+///
+/// # Safety
+///
+/// Must only be called by `cpu` itself, since each queue has a single consumer.
+pub unsafe fn run_next_job(cpu: usize) -> bool {
+    let idx = match QUEUES[cpu].pop() {
+        Some(idx) => idx as usize,
+        None => return false,
+    };
+    let packet = &PACKETS[idx];
+
+    // The handle may be dropped concurrently, setting `DETACHED`, which is kept.
+    packet.state.fetch_add(RUNNING - QUEUED, Ordering::Acquire);
+    (*packet.run.get())((*packet.storage.get()).0.as_mut_ptr() as *mut u8);
+
+    let mut state = packet.state.load(Ordering::Relaxed);
+    loop {
+        if state & DETACHED != 0 {
+            // Nobody is going to collect the result.
+            (*packet.drop_result.get())((*packet.storage.get()).0.as_mut_ptr() as *mut u8);
+            free_packet(packet);
+            break;
+        }
+        match packet
+            .state
+            .compare_exchange_weak(state, DONE, Ordering::Release, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                cortex_a::asm::sev();
+                break;
+            }
+            Err(cur) => state = cur,
+        }
+    }
+    true
+}
+
+/// Handle to a job sent to another core with [`spawn_on`]. Dropping it detaches the job, which
+/// still runs, but whose result is dropped.
+pub struct JoinHandle<T> {
+    packet: &'static Packet,
+    _marker: PhantomData<T>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Checks whether the job finished running.
+    pub fn is_finished(&self) -> bool {
+        self.packet.state.load(Ordering::Acquire) == DONE
+    }
+
+    /// Gets the result of the job if it finished, or gives the handle back otherwise.
+    pub fn try_join(self) -> Result<T, Self> {
+        if !self.is_finished() {
+            return Err(self);
+        }
+        // SAFETY: The job is done, so the storage holds its result, which is moved out before
+        // freeing the packet.
+        let result = unsafe { ptr::read((*self.packet.storage.get()).0.as_ptr() as *const T) };
+        free_packet(self.packet);
+        mem::forget(self);
+        Ok(result)
+    }
+
+    /// Waits for the job to finish and gets its result. The core sleeps with `wfe` while waiting.
+    pub fn join(mut self) -> T {
+        loop {
+            match self.try_join() {
+                Ok(result) => return result,
+                Err(handle) => self = handle,
+            }
+            cortex_a::asm::wfe();
+        }
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        let prev = self.packet.state.fetch_or(DETACHED, Ordering::AcqRel);
+        if prev == DONE {
+            // SAFETY: The job is done, so the storage holds its result.
+            unsafe {
+                (*self.packet.drop_result.get())(
+                    (*self.packet.storage.get()).0.as_mut_ptr() as *mut u8
+                )
+            };
+            free_packet(self.packet);
+        }
+    }
+}
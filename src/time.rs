@@ -0,0 +1,216 @@
+//! Time keeping with the ARM generic timer.
+//!
+//! [`Instant`]s are read from the physical counter `CNTPCT_EL0`, which is shared by all cores and
+//! runs at the fixed frequency in `CNTFRQ_EL0`, independently of the CPU clock. Each core also has
+//! its own physical timer, which raises [`LocalIrq::PhysicalTimer`] through the ARM local interrupt
+//! controller and is used for the one-shot and periodic timers of [`start_oneshot`] and
+//! [`start_periodic`].
+
+use core::{
+    fmt, ops,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use cortex_a::asm::barrier;
+use cortex_a::registers::{CNTFRQ_EL0, CNTPCT_EL0, CNTP_CTL_EL0, CNTP_CVAL_EL0};
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::irq::{self, IrqError, IrqSource, LocalIrq};
+use crate::smp::NUM_CORES;
+use crate::utils::get_cpu;
+
+pub use core::time::Duration;
+
+/// A handler called from the timer interrupt when a timer of the current core expires.
+pub type TimerHandler = fn();
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Bit of the counter that generates the event stream used by [`sleep`]. With the 62.5 MHz counter
+/// of the Raspberry Pi 3 an event is generated every 16 µs.
+const EVENT_STREAM_BIT: u64 = 9;
+
+const CNTP_CTL_ENABLE: u64 = 1 << 0;
+
+/// Timer of each core. A zero handler means the timer is not running, a zero period that it is a
+/// one-shot timer.
+struct CoreTimer {
+    handler: AtomicUsize,
+    period: AtomicU64,
+}
+
+static TIMERS: [CoreTimer; NUM_CORES] = [const {
+    CoreTimer {
+        handler: AtomicUsize::new(0),
+        period: AtomicU64::new(0),
+    }
+}; NUM_CORES];
+
+/// Gets the frequency of the counter, in Hz.
+#[inline(always)]
+pub fn frequency() -> u64 {
+    CNTFRQ_EL0.get()
+}
+
+/// Reads the counter. The `isb` keeps the read from being done earlier than where it appears.
+#[inline(always)]
+fn ticks() -> u64 {
+    barrier::isb(barrier::SY);
+    CNTPCT_EL0.get()
+}
+
+fn duration_to_ticks(d: Duration) -> u64 {
+    let freq = frequency();
+    d.as_secs()
+        .saturating_mul(freq)
+        .saturating_add(d.subsec_nanos() as u64 * freq / NANOS_PER_SEC)
+}
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    let freq = frequency();
+    let secs = ticks / freq;
+    let nanos = (ticks % freq) * NANOS_PER_SEC / freq;
+    Duration::new(secs, nanos as u32)
+}
+
+/// A point in time of the monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Gets the current time.
+    pub fn now() -> Self {
+        Instant(ticks())
+    }
+
+    /// Time since the counter started, normally when the system was powered on.
+    pub fn since_boot(&self) -> Duration {
+        ticks_to_duration(self.0)
+    }
+
+    /// Time elapsed from `earlier` to `self`, or zero if `earlier` is later than `self`.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time elapsed since `self`.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Adds `d` to `self`, or returns `None` if the counter would overflow.
+    pub fn checked_add(&self, d: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(d)).map(Instant)
+    }
+}
+
+impl ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, d: Duration) -> Instant {
+        self.checked_add(d)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl ops::Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = self.since_boot();
+        write!(f, "{}.{:06}", d.as_secs(), d.subsec_micros())
+    }
+}
+
+/// Busy waits for `d`. Meant for the short delays required by devices.
+pub fn spin_for(d: Duration) {
+    let deadline = ticks().saturating_add(duration_to_ticks(d));
+    while ticks() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Waits for `d` in low power mode, woken up by the event stream enabled by [`init_core`].
+pub fn sleep(d: Duration) {
+    let deadline = ticks().saturating_add(duration_to_ticks(d));
+    while ticks() < deadline {
+        cortex_a::asm::wfe();
+    }
+}
+
+/// Registers the timer interrupt handler. Must be called once by the boot core, after
+/// [`irq::init`].
+pub fn init() -> Result<(), IrqError> {
+    irq::register(IrqSource::Local(LocalIrq::PhysicalTimer), handle_timer_irq)
+}
+
+/// Enables the event stream used by [`sleep`] and the timer interrupt of the current core. Must be
+/// called on every core.
+pub fn init_core() {
+    let mut cntkctl: u64;
+    unsafe {
+        core::arch::asm!("mrs {}, CNTKCTL_EL1", out(reg) cntkctl);
+        // EVNTI selects the counter bit, EVNTEN enables the stream.
+        cntkctl = (cntkctl & !0xf0) | EVENT_STREAM_BIT << 4 | 1 << 2;
+        core::arch::asm!("msr CNTKCTL_EL1, {}", in(reg) cntkctl);
+    }
+    CNTP_CTL_EL0.set(0);
+    irq::enable(IrqSource::Local(LocalIrq::PhysicalTimer));
+}
+
+fn start(deadline: u64, period: u64, handler: TimerHandler) {
+    let timer = &TIMERS[get_cpu() as usize];
+    irq::without_interrupts(|| {
+        timer.period.store(period, Ordering::Relaxed);
+        timer.handler.store(handler as usize, Ordering::Relaxed);
+        CNTP_CVAL_EL0.set(deadline);
+        CNTP_CTL_EL0.set(CNTP_CTL_ENABLE);
+    });
+}
+
+/// Calls `handler` once on the current core after `after`, replacing any timer running on it.
+pub fn start_oneshot(after: Duration, handler: TimerHandler) {
+    start(ticks().saturating_add(duration_to_ticks(after)), 0, handler)
+}
+
+/// Calls `handler` on the current core every `period`, replacing any timer running on it.
+pub fn start_periodic(period: Duration, handler: TimerHandler) {
+    let period = duration_to_ticks(period).max(1);
+    start(ticks().saturating_add(period), period, handler)
+}
+
+/// Stops the timer of the current core.
+pub fn cancel() {
+    irq::without_interrupts(|| {
+        CNTP_CTL_EL0.set(0);
+        TIMERS[get_cpu() as usize]
+            .handler
+            .store(0, Ordering::Relaxed);
+    });
+}
+
+fn handle_timer_irq() {
+    let timer = &TIMERS[get_cpu() as usize];
+    let handler = timer.handler.load(Ordering::Relaxed);
+    let period = timer.period.load(Ordering::Relaxed);
+    if period == 0 {
+        CNTP_CTL_EL0.set(0);
+        timer.handler.store(0, Ordering::Relaxed);
+    } else {
+        // Relative to the previous deadline, so that the period doesn't drift with the latency of
+        // the interrupt.
+        CNTP_CVAL_EL0.set(CNTP_CVAL_EL0.get().wrapping_add(period));
+    }
+    if handler != 0 {
+        // SAFETY: Only `TimerHandler`s are stored in the timers.
+        unsafe { core::mem::transmute::<usize, TimerHandler>(handler)() };
+    } else {
+        CNTP_CTL_EL0.set(0);
+    }
+}
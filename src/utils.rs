@@ -1,3 +1,5 @@
+pub mod ring_buffer;
+
 use cortex_a::asm;
 use tock_registers::interfaces::Readable;
 
@@ -10,20 +12,6 @@ pub(crate) fn inifinite_loop() -> ! {
     }
 }
 
-/// Does nothing for approximately `cycles` CPU cycles.
-pub fn delay_cycles(cycles: usize) {
-    unsafe {
-        core::arch::asm!(
-            "cbz {count:x}, 2f",
-            "1:",
-            "  subs {count:x}, {count:x}, #1",
-            "  bne 1b",
-            "2:",
-            count = in(reg) cycles,
-        );
-    }
-}
-
 /// Gets the current cpu id.
 pub fn get_cpu() -> u64 {
     use cortex_a::registers::MPIDR_EL1;
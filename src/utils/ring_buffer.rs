@@ -0,0 +1,87 @@
+//! Lock-free single-producer single-consumer ring buffer of bytes.
+//!
+//! `head` is only written by the consumer and `tail` only by the producer. Both count bytes since
+//! the buffer was created and wrap around, so the buffer can use all `N` bytes and the length is
+//! simply `tail - head`.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Ring buffer of `N` bytes, where `N` is a power of two.
+pub struct RingBuffer<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// SAFETY: A byte is only accessed by the producer before `tail` is advanced past it, and by the
+// consumer after that and before `head` is advanced past it.
+unsafe impl<const N: usize> Sync for RingBuffer<N> {}
+
+impl<const N: usize> RingBuffer<N> {
+    const MASK: usize = {
+        assert!(
+            N.is_power_of_two(),
+            "ring buffer size must be a power of two"
+        );
+        N - 1
+    };
+
+    /// Creates an empty ring buffer.
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: UnsafeCell::new([0; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of bytes in the buffer.
+    pub fn len(&self) -> usize {
+        self.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    /// Whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the buffer is full.
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    /// Pushes `byte`, or returns it back if the buffer is full.
+    ///
+    /// # Safety
+    ///
+    /// There must be a single producer at a time.
+    pub unsafe fn push(&self, byte: u8) -> Result<(), u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return Err(byte);
+        }
+        (*self.buf.get())[tail & Self::MASK] = byte;
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Pops the oldest byte, if any.
+    ///
+    /// # Safety
+    ///
+    /// There must be a single consumer at a time.
+    pub unsafe fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if self.tail.load(Ordering::Acquire) == head {
+            return None;
+        }
+        let byte = (*self.buf.get())[head & Self::MASK];
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(byte)
+    }
+}
@@ -1,3 +1,4 @@
+mod symbols;
 mod utils;
 
 use std::{ env, io, fs };
@@ -20,17 +21,28 @@ fn main() {
     let subcommand = env::args().nth(1);
     let args = env::args().skip_while(|arg| arg != "--").skip(1);
     let is_debug = env::args().find(|arg| arg == "--debug").is_some();
+    let is_pl011 = env::args().any(|arg| arg == "--pl011");
+    let is_display = env::args().any(|arg| arg == "--display");
+    let mut features = Vec::new();
+    if is_pl011 { features.push("console-pl011"); }
+    if is_display { features.push("framebuffer-console"); }
+    if env::args().any(|arg| arg == "--reboot-on-panic") { features.push("reboot-on-panic"); }
     let res = match subcommand.as_deref() {
-        Some("build") => build(is_debug, args),
-        Some("qemu")  => build(is_debug, args).and_then(|_| qemu()),
-        Some("debug") => build(true, args).and_then(|_| qemu()),
-        Some("gdb") => build(true, args).and_then(|_| qemu_gdb()),
+        Some("build") => build(is_debug, &features, args),
+        Some("qemu")  => build(is_debug, &features, args).and_then(|_| qemu(is_pl011, is_display)),
+        Some("debug") => build(true, &features, args).and_then(|_| qemu(is_pl011, is_display)),
+        Some("gdb") => build(true, &features, args).and_then(|_| qemu_gdb(is_pl011, is_display)),
         Some("clippy") => clippy(),
 
         _ => {
             eprintln!("usage: cargo xtask <task>");
             eprintln!("Tasks:");
             eprintln!("    build - build the OS");
+            eprintln!("Options:");
+            eprintln!("    --debug - build without optimizations");
+            eprintln!("    --pl011 - use the PL011 instead of the Mini UART for the console");
+            eprintln!("    --display - show the console on the framebuffer, in a QEMU window");
+            eprintln!("    --reboot-on-panic - reset the board a few seconds after a panic");
             Ok(())
         }
     };
@@ -40,7 +52,7 @@ fn main() {
     }
 }
 
-fn build(is_debug: bool, args: impl Iterator<Item = String>) -> Result {
+fn build(is_debug: bool, features: &[&str], args: impl Iterator<Item = String>) -> Result {
     check_deps()?;
 
     let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
@@ -48,10 +60,12 @@ fn build(is_debug: bool, args: impl Iterator<Item = String>) -> Result {
     cmd.arg("rustc")
        .args(&["--target", TARGET]);
     if !is_debug { cmd.arg("--release"); }
+    if !features.is_empty() { cmd.args(&["--features", &features.join(",")]); }
     cmd.arg("--")
        .args(&["-C", &format!("link-arg=-T{}", LINKER_FILE)])
        .args(&["-C", "target-cpu=cortex-a53"])
        .args(&["-C", "relocation-model=static"])
+       .args(&["-C", "force-frame-pointers=yes"])
        .args(&["-D", "warnings"])
        .args(&["-D", "missing_docs"])
        .args(args);
@@ -74,10 +88,13 @@ fn build(is_debug: bool, args: impl Iterator<Item = String>) -> Result {
         fs::copy(KERNEL_RELEASE, KERNEL_ELF)?;
     }
 
+    print_info(format!("Embed symbols in {KERNEL_ELF}"));
+    symbols::embed(KERNEL_ELF)?;
+
     let mut cmd = Command::new("rust-objcopy");
     cmd.args(&["-O", "binary"]);
     if !is_debug { cmd.arg("--strip-all"); }
-    cmd.arg(if is_debug { KERNEL_DEBUG } else { KERNEL_RELEASE })
+    cmd.arg(KERNEL_ELF)
        .arg(KERNEL_BIN);
 
     print_command(&cmd);
@@ -93,10 +110,10 @@ fn build(is_debug: bool, args: impl Iterator<Item = String>) -> Result {
     Ok(())
 }
 
-fn qemu() -> Result {
+fn qemu(is_pl011: bool, is_display: bool) -> Result {
     check_qemu()?;
 
-    let mut qemu_cmd = qemu_cmd(KERNEL_ELF);
+    let mut qemu_cmd = qemu_cmd(KERNEL_ELF, is_pl011, is_display);
     print_command(&qemu_cmd);
 
     if qemu_cmd
@@ -110,10 +127,10 @@ fn qemu() -> Result {
     Ok(())
 }
 
-fn qemu_gdb() -> Result {
+fn qemu_gdb(is_pl011: bool, is_display: bool) -> Result {
     check_qemu()?;
 
-    let mut qemu_cmd = qemu_cmd(KERNEL_ELF);
+    let mut qemu_cmd = qemu_cmd(KERNEL_ELF, is_pl011, is_display);
     qemu_cmd
         .arg("-S")
         .arg("-s");
@@ -151,15 +168,22 @@ fn clippy() -> Result {
     Ok(())
 }
 
-fn qemu_cmd(fname: &str) -> Command {
+/// The first serial port is the PL011 and the second is the Mini UART. Only the one backing the
+/// console is connected to stdio. The framebuffer is only shown when `is_display` is set.
+fn qemu_cmd(fname: &str, is_pl011: bool, is_display: bool) -> Command {
+    let (pl011, mini_uart) = if is_pl011 { ("stdio", "null") } else { ("null", "stdio") };
     let mut qemu_cmd = Command::new("qemu-system-aarch64");
     qemu_cmd
         .args(&["-M", "raspi3b"])
         // .args(&["-d", "in_asm"])
-        .args(&["-display", "none"])
-        .args(&["-serial", "null"])
-        .args(&["-serial", "stdio"])
+        .args(&["-serial", pl011])
+        .args(&["-serial", mini_uart])
         .args(&["-kernel", fname]);
+
+    if !is_display {
+        qemu_cmd.args(&["-display", "none"]);
+    }
+
     qemu_cmd
 }
 
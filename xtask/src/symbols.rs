@@ -0,0 +1,244 @@
+//! Fills in the symbol table of the kernel, used to symbolize backtraces. See `src/backtrace.rs`
+//! for the layout of the table.
+
+use std::convert::TryInto;
+use std::fs;
+
+use crate::utils::*;
+use crate::AnyErr;
+
+const TABLE_SYMBOL: &str = "KERNEL_SYMBOLS";
+const TABLE_MAGIC: &[u8] = b"KSYMTAB\0";
+const TABLE_SIZE: usize = 128 * 1024;
+const ENTRY_SIZE: usize = 16;
+const HEADER_SIZE: usize = 8;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const STT_OBJECT: u8 = 1;
+
+struct Section {
+    kind: u32,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    entsize: u64,
+}
+
+struct Symbol {
+    name: String,
+    kind: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+fn u16_at(data: &[u8], off: usize) -> Result<u16, AnyErr> {
+    Ok(u16::from_le_bytes(data.get(off..off + 2).ok_or("truncated ELF")?.try_into()?))
+}
+
+fn u32_at(data: &[u8], off: usize) -> Result<u32, AnyErr> {
+    Ok(u32::from_le_bytes(data.get(off..off + 4).ok_or("truncated ELF")?.try_into()?))
+}
+
+fn u64_at(data: &[u8], off: usize) -> Result<u64, AnyErr> {
+    Ok(u64::from_le_bytes(data.get(off..off + 8).ok_or("truncated ELF")?.try_into()?))
+}
+
+fn str_at(data: &[u8], off: usize) -> Result<&str, AnyErr> {
+    let bytes = data.get(off..).ok_or("truncated ELF")?;
+    let len = bytes.iter().position(|&b| b == 0).ok_or("unterminated string in ELF")?;
+    Ok(std::str::from_utf8(&bytes[..len])?)
+}
+
+fn sections(elf: &[u8]) -> Result<Vec<Section>, AnyErr> {
+    if elf.get(..4) != Some(b"\x7fELF") || elf[4] != 2 || elf[5] != 1 {
+        return Err("kernel is not a little endian ELF64".into());
+    }
+    let shoff = u64_at(elf, 0x28)? as usize;
+    let shentsize = u16_at(elf, 0x3a)? as usize;
+    let shnum = u16_at(elf, 0x3c)? as usize;
+    (0..shnum)
+        .map(|i| {
+            let sh = shoff + i * shentsize;
+            Ok(Section {
+                kind: u32_at(elf, sh + 0x04)?,
+                addr: u64_at(elf, sh + 0x10)?,
+                offset: u64_at(elf, sh + 0x18)?,
+                size: u64_at(elf, sh + 0x20)?,
+                link: u32_at(elf, sh + 0x28)?,
+                entsize: u64_at(elf, sh + 0x38)?,
+            })
+        })
+        .collect()
+}
+
+fn symbols(elf: &[u8], sections: &[Section]) -> Result<Vec<Symbol>, AnyErr> {
+    let symtab = sections
+        .iter()
+        .find(|sh| sh.kind == SHT_SYMTAB)
+        .ok_or("kernel has no symbol table, was it stripped?")?;
+    let strtab = sections.get(symtab.link as usize).ok_or("bad string table index")?;
+    let count = (symtab.size / symtab.entsize.max(1)) as usize;
+    (0..count)
+        .map(|i| {
+            let sym = symtab.offset as usize + i * symtab.entsize as usize;
+            let name = str_at(elf, strtab.offset as usize + u32_at(elf, sym)? as usize)?;
+            Ok(Symbol {
+                name: name.to_string(),
+                kind: elf[sym + 4] & 0xf,
+                shndx: u16_at(elf, sym + 6)?,
+                value: u64_at(elf, sym + 8)?,
+                size: u64_at(elf, sym + 16)?,
+            })
+        })
+        .collect()
+}
+
+/// Demangles a symbol with the legacy Rust mangling, `_ZN` followed by length prefixed path
+/// components and `E`. The trailing hash is dropped. Other symbols are returned as they are.
+pub fn demangle(name: &str) -> String {
+    let mut rest = match name.strip_prefix("_ZN") {
+        Some(rest) => rest,
+        None => return name.to_string(),
+    };
+    let mut parts = Vec::new();
+    while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit()).filter(|&n| n > 0) {
+        let len: usize = match rest[..digits].parse() {
+            Ok(len) => len,
+            Err(_) => return name.to_string(),
+        };
+        match rest.get(digits..digits + len) {
+            Some(part) => parts.push(part),
+            None => return name.to_string(),
+        }
+        rest = &rest[digits + len..];
+    }
+    if !rest.starts_with('E') || parts.is_empty() {
+        return name.to_string();
+    }
+    if let Some(hash) = parts.last().and_then(|part| part.strip_prefix('h')) {
+        if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            parts.pop();
+        }
+    }
+    parts.iter().map(|part| unescape(part)).collect::<Vec<_>>().join("::")
+}
+
+fn unescape(mut part: &str) -> String {
+    // Components that would start with an escape are prefixed with an underscore.
+    if part.starts_with("_$") {
+        part = &part[1..];
+    }
+    let mut out = String::new();
+    while !part.is_empty() {
+        if let Some(rest) = part.strip_prefix("..") {
+            out.push_str("::");
+            part = rest;
+        } else if let Some(rest) = part.strip_prefix('$') {
+            let end = match rest.find('$') {
+                Some(end) => end,
+                None => {
+                    out.push_str(part);
+                    break;
+                }
+            };
+            let escaped = match &rest[..end] {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                code => code
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32),
+            };
+            match escaped {
+                Some(c) => {
+                    out.push(c);
+                    part = &rest[end + 1..];
+                }
+                None => {
+                    out.push('$');
+                    part = rest;
+                }
+            }
+        } else {
+            let c = part.chars().next().unwrap();
+            out.push(c);
+            part = &part[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// Writes the table of the function symbols of the kernel ELF at `path` into its
+/// `KERNEL_SYMBOLS`, truncating it if it doesn't fit.
+pub fn embed(path: &str) -> Result<(), AnyErr> {
+    let mut elf = fs::read(path)?;
+    let sections = sections(&elf)?;
+    let symbols = symbols(&elf, &sections)?;
+
+    let table = symbols
+        .iter()
+        .find(|sym| sym.kind == STT_OBJECT && sym.name == TABLE_SYMBOL)
+        .ok_or("kernel has no symbol table to fill in")?;
+    let section = sections.get(table.shndx as usize).ok_or("bad section index")?;
+    let offset = (section.offset + table.value - section.addr) as usize;
+    if elf.get(offset..offset + TABLE_MAGIC.len()) != Some(TABLE_MAGIC) {
+        return Err(format!("{} doesn't start with its magic", TABLE_SYMBOL).into());
+    }
+
+    let mut funcs: Vec<_> = symbols
+        .iter()
+        .filter(|sym| sym.kind == STT_FUNC && sym.size > 0)
+        .map(|sym| (sym.value, sym.size, demangle(&sym.name)))
+        .collect();
+    funcs.sort_by_key(|&(addr, ..)| addr);
+    funcs.dedup_by_key(|&mut (addr, ..)| addr);
+
+    // Finds how many symbols fit, each taking an entry and its name.
+    let mut used = HEADER_SIZE;
+    let mut count = 0;
+    for (_, _, name) in &funcs {
+        let len = 1 + name.len().min(u8::MAX as usize);
+        if used + ENTRY_SIZE + len > TABLE_SIZE {
+            break;
+        }
+        used += ENTRY_SIZE + len;
+        count += 1;
+    }
+    if count < funcs.len() {
+        print_warn(format!(
+            "Symbol table is full, dropped {} of {} symbols",
+            funcs.len() - count,
+            funcs.len()
+        ));
+    }
+    let funcs = &funcs[..count];
+
+    let mut data = Vec::with_capacity(used);
+    let mut names = Vec::new();
+    data.extend_from_slice(&(count as u32).to_le_bytes());
+    data.extend_from_slice(&[0; HEADER_SIZE - 4]);
+    for (addr, size, name) in funcs {
+        let name = &name.as_bytes()[..name.len().min(u8::MAX as usize)];
+        data.extend_from_slice(&addr.to_le_bytes());
+        data.extend_from_slice(&(*size as u32).to_le_bytes());
+        data.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.push(name.len() as u8);
+        names.extend_from_slice(name);
+    }
+    data.extend_from_slice(&names);
+
+    let start = offset + TABLE_MAGIC.len();
+    elf[start..start + data.len()].copy_from_slice(&data);
+    fs::write(path, elf)?;
+    print_info(format!("Embedded {} symbols in {} ({} bytes)", count, path, data.len()));
+    Ok(())
+}
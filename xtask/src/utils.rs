@@ -26,3 +26,7 @@ pub fn print_command(cmd: &Command) {
     }
     println!();
 }
+
+pub fn print_warn(s: impl AsRef<str>) {
+    println!("\t{}[WARN]{}\t{}", YELLOW, RESET, s.as_ref());
+}